@@ -0,0 +1,10 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate macaroons;
+
+use macaroons::format::Macaroon;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Macaroon::deserialize(data.to_vec());
+});
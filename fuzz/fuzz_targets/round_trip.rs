@@ -0,0 +1,60 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate macaroons;
+
+use macaroons::format::Macaroon;
+use macaroons::token::{Encoding, Token};
+use macaroons::v1::V1Token;
+use macaroons::v2::V2Token;
+
+fn assert_same(token: &V2Token, parsed_token: &V2Token) {
+    assert_eq!(token.identifier, parsed_token.identifier);
+    assert_eq!(token.location, parsed_token.location);
+    assert_eq!(token.caveats.len(), parsed_token.caveats.len());
+
+    for (caveat, parsed_caveat) in token.caveats.iter().zip(&parsed_token.caveats) {
+        assert_eq!(caveat.caveat_id, parsed_caveat.caveat_id);
+        assert_eq!(caveat.verification_id, parsed_caveat.verification_id);
+        assert_eq!(caveat.caveat_location, parsed_caveat.caveat_location);
+    }
+
+    assert_eq!(token.tag, parsed_token.tag);
+}
+
+// The streamed forms must match serialize() and the precomputed lengths
+fn assert_streams<T: Token>(token: &T, serialized: &[u8], encoding: Encoding) {
+    let mut streamed = Vec::new();
+    token.serialize_into(&mut streamed, encoding).unwrap();
+
+    assert_eq!(serialized, &streamed[..]);
+    assert_eq!(serialized.len(), token.serialized_len(encoding).unwrap());
+}
+
+fuzz_target!(|data: &[u8]| {
+    let macaroon = match Macaroon::deserialize(data.to_vec()) {
+        Ok(macaroon) => macaroon,
+        Err(_) => return,
+    };
+
+    match macaroon {
+        Macaroon::V1(token) => {
+            let serialized = token.serialize().unwrap();
+            assert_streams(&token, &serialized, Encoding::Base64);
+
+            let parsed_token = V1Token::deserialize(serialized).unwrap();
+            assert_same(&V2Token::from(token), &V2Token::from(parsed_token));
+        }
+        Macaroon::V2(token) => {
+            let serialized = token.serialize().unwrap();
+            assert_streams(&token, &serialized, Encoding::Raw);
+
+            let parsed_token = V2Token::deserialize(serialized).unwrap();
+            assert_same(&token, &parsed_token);
+        }
+        Macaroon::V2J(token) => {
+            let parsed_token = V2Token::deserialize_json(token.serialize_json().unwrap()).unwrap();
+            assert_same(&token, &parsed_token);
+        }
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate macaroons;
+
+use macaroons::token::Token;
+use macaroons::v1::V1Token;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = V1Token::deserialize_binary(data);
+    let _ = V1Token::deserialize(data.to_vec());
+});
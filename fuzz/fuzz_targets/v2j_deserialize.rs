@@ -0,0 +1,10 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate macaroons;
+
+use macaroons::v2::V2Token;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = V2Token::deserialize_json(data.to_vec());
+});
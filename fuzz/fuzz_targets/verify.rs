@@ -0,0 +1,77 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate macaroons;
+
+use macaroons::caveat::Caveat;
+use macaroons::error::FailureKind;
+use macaroons::key::RootKey;
+use macaroons::token::Token;
+use macaroons::v1::V1Token;
+use macaroons::v2::V2Token;
+use macaroons::verifier::ByteFunc;
+
+// (predicate or caveat id, caveat key for third-party caveats, accepted by the verifier)
+type CaveatInput = (Vec<u8>, Option<Vec<u8>>, bool);
+
+fuzz_target!(|input: (Vec<u8>, Vec<u8>, Vec<CaveatInput>)| {
+    let (key, identifier, caveat_inputs) = input;
+    let key = RootKey::from(key);
+
+    let mut token = V1Token::new(&key, identifier, None);
+    let mut accepted: Vec<Vec<u8>> = Vec::new();
+    let mut discharges: Vec<V1Token> = Vec::new();
+
+    for (caveat_id, caveat_key, accept) in caveat_inputs {
+        // Discharges are looked up by caveat id, so only the first
+        // third-party caveat with a given id gets to be one
+        let is_unique = !discharges.iter().any(|discharge| discharge.identifier == caveat_id);
+
+        match caveat_key {
+            Some(caveat_key) if is_unique => {
+                token = token.add_caveat(&Caveat::third_party(caveat_key.clone(),
+                                                              caveat_id.clone(),
+                                                              Vec::from("fuzz")));
+                discharges.push(V1Token::new(&RootKey::from(caveat_key), caveat_id, None));
+            }
+            _ => {
+                token = token.add_caveat(&Caveat::first_party(caveat_id.clone()));
+
+                if accept {
+                    accepted.push(caveat_id);
+                }
+            }
+        }
+    }
+
+    // A predicate appearing as both accepted and not accepted is accepted
+    let expected = token.caveats
+        .iter()
+        .filter(|caveat| !caveat.is_third_party())
+        .all(|caveat| accepted.contains(&caveat.caveat_id));
+
+    let discharges: Vec<V1Token> = discharges.iter()
+        .map(|discharge| token.prepare_for_request(discharge))
+        .collect();
+
+    let verifier = ByteFunc(|predicate: &[u8]| accepted.iter().any(|a| &a[..] == predicate));
+
+    assert!(token.authenticate_without_verifying(&key).is_ok());
+    assert_eq!(expected,
+               token.verify_with_discharges(&key, &verifier, &discharges).is_ok());
+
+    let mut wrong_key = key.as_bytes().to_vec();
+    wrong_key.push(0);
+    let wrong_key = RootKey::from(wrong_key);
+    let error = token.verify_with_discharges_detailed(&wrong_key, &verifier, &discharges)
+        .unwrap_err();
+    assert_eq!(FailureKind::Signature, error.kind);
+
+    // Verification must be unaffected by a trip through the binary formats
+    let token = V2Token::from(token);
+    let discharges: Vec<V2Token> = discharges.into_iter().map(V2Token::from).collect();
+    let parsed_token = V2Token::deserialize(token.serialize().unwrap()).unwrap();
+
+    assert_eq!(expected,
+               parsed_token.verify_with_discharges(&key, &verifier, &discharges).is_ok());
+});
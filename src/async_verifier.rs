@@ -0,0 +1,190 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::future::{self, Future};
+use core::mem;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use error::{VerificationError, VerificationResult};
+use key::RootKey;
+use token::Token;
+use verifier::{CaveatVerifier, Outcome};
+
+// The pending check of a single caveat. Checks only borrow the verifier, so
+// anything needed from the caveat must be copied out of it before returning.
+// Checks are Send, so verification can be spawned onto multithreaded
+// executors.
+pub type CheckFuture<'a> = Pin<Box<dyn Future<Output = Outcome> + Send + 'a>>;
+
+// Replays verification of a token against a pass over its caveats
+type Replay<'a> = Box<dyn Fn(&Pass) -> VerificationResult + Send + 'a>;
+
+// The async counterpart to CaveatVerifier, for caveats that can only be
+// checked with I/O, e.g. looking up "revocation-id = 42" in a database. Any
+// executor can drive the checks, as they're plain std futures.
+pub trait AsyncVerifier {
+    fn check_first_party_async<'a>(&'a self, _caveat: &[u8]) -> CheckFuture<'a> {
+        Box::pin(future::ready(Outcome::Unrecognized))
+    }
+
+    fn check_third_party_async<'a>(&'a self, _caveat: &[u8]) -> CheckFuture<'a> {
+        Box::pin(future::ready(Outcome::Unrecognized))
+    }
+}
+
+// Synchronous verifiers check caveats straight away
+impl<V: CaveatVerifier> AsyncVerifier for V {
+    fn check_first_party_async<'a>(&'a self, caveat: &[u8]) -> CheckFuture<'a> {
+        Box::pin(future::ready(self.check_first_party(caveat)))
+    }
+
+    fn check_third_party_async<'a>(&'a self, caveat: &[u8]) -> CheckFuture<'a> {
+        Box::pin(future::ready(self.check_third_party(caveat)))
+    }
+}
+
+// Whether a caveat is third-party, and its predicate or caveat id
+type Check = (bool, Vec<u8>);
+
+// A synchronous pass over a token and its discharges. Without outcomes, every
+// caveat is recorded and assumed to hold, which checks the signatures and
+// discharges before anything is looked up. With them, the outcomes of the
+// checks are replayed, giving the same error synchronous verification would.
+struct Pass {
+    checks: RefCell<BTreeSet<Check>>,
+    outcomes: Option<BTreeMap<Check, Outcome>>,
+}
+
+impl Pass {
+    fn check(&self, third_party: bool, caveat: &[u8]) -> Outcome {
+        let check = (third_party, caveat.to_vec());
+
+        match self.outcomes {
+            Some(ref outcomes) => outcomes.get(&check).cloned().unwrap_or(Outcome::Unrecognized),
+            None => {
+                self.checks.borrow_mut().insert(check);
+                Outcome::Satisfied
+            }
+        }
+    }
+}
+
+impl CaveatVerifier for &Pass {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        self.check(false, caveat)
+    }
+
+    fn check_third_party(&self, caveat: &[u8]) -> Outcome {
+        self.check(true, caveat)
+    }
+}
+
+// The future verification of a token. Each distinct caveat is checked once,
+// with all the checks running concurrently, and only after the signatures and
+// discharges have been found to be valid.
+pub struct Verification<'a> {
+    verify: Replay<'a>,
+    pending: Vec<(Check, CheckFuture<'a>)>,
+    outcomes: BTreeMap<Check, Outcome>,
+    failure: Option<VerificationError>,
+}
+
+impl<'a> Verification<'a> {
+    fn start<V: AsyncVerifier>(verifier: &'a V, verify: Replay<'a>) -> Verification<'a> {
+        let pass = Pass {
+            checks: RefCell::new(BTreeSet::new()),
+            outcomes: None,
+        };
+
+        if let Err(error) = verify(&pass) {
+            return Verification {
+                verify,
+                pending: Vec::new(),
+                outcomes: BTreeMap::new(),
+                failure: Some(error),
+            };
+        }
+
+        let pending = pass.checks
+            .into_inner()
+            .into_iter()
+            .map(|(third_party, caveat)| {
+                let future = if third_party {
+                    verifier.check_third_party_async(&caveat)
+                } else {
+                    verifier.check_first_party_async(&caveat)
+                };
+
+                ((third_party, caveat), future)
+            })
+            .collect();
+
+        Verification {
+            verify,
+            pending,
+            outcomes: BTreeMap::new(),
+            failure: None,
+        }
+    }
+}
+
+impl<'a> Future for Verification<'a> {
+    type Output = VerificationResult;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<VerificationResult> {
+        let verification = self.get_mut();
+
+        if let Some(failure) = verification.failure.take() {
+            return Poll::Ready(Err(failure));
+        }
+
+        let outcomes = &mut verification.outcomes;
+        verification.pending.retain_mut(|&mut (ref check, ref mut future)| {
+            match future.as_mut().poll(context) {
+                Poll::Ready(outcome) => {
+                    outcomes.insert(check.clone(), outcome);
+                    false
+                }
+                Poll::Pending => true,
+            }
+        });
+
+        if !verification.pending.is_empty() {
+            return Poll::Pending;
+        }
+
+        let pass = Pass {
+            checks: RefCell::new(BTreeSet::new()),
+            outcomes: Some(mem::take(&mut verification.outcomes)),
+        };
+
+        Poll::Ready((verification.verify)(&pass))
+    }
+}
+
+// Verify a token on its own, leaving its third-party caveats to the verifier
+pub fn verify<'a, T, V>(token: &'a T, key: &RootKey, verifier: &'a V) -> Verification<'a>
+    where T: Token + Sync,
+          V: AsyncVerifier
+{
+    let key = key.clone();
+    Verification::start(verifier, Box::new(move |pass| token.verify_detailed(&key, pass)))
+}
+
+// Verify a token along with the discharges for its third-party caveats
+pub fn verify_with_discharges<'a, T, V>(token: &'a T,
+                                        key: &RootKey,
+                                        verifier: &'a V,
+                                        discharges: &'a [T])
+                                        -> Verification<'a>
+    where T: Token + Sync,
+          V: AsyncVerifier
+{
+    let key = key.clone();
+    Verification::start(verifier,
+                        Box::new(move |pass| {
+                            token.verify_with_discharges_detailed(&key, pass, discharges)
+                        }))
+}
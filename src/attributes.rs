@@ -0,0 +1,120 @@
+use alloc::borrow::ToOwned;
+use alloc::collections::btree_map::{self, BTreeMap};
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use core::cell::RefCell;
+use core::result;
+use core::str::FromStr;
+
+use caveat::Predicate;
+use error::VerificationError;
+use key::RootKey;
+use token::Token;
+use verifier::{CaveatVerifier, Outcome};
+
+// The attributes declared by the satisfied "key = value" and "key in a,b"
+// caveats of a token and its discharges. Each caveat with the same key
+// narrows the values allowed for it, so "scope in read,write" followed by
+// "scope = read" leaves only "read", and disjoint caveats leave no values.
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct Attributes {
+    values: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Attributes {
+    pub fn new() -> Attributes {
+        Attributes::default()
+    }
+
+    // The value of an attribute, if it was narrowed down to exactly one
+    pub fn get(&self, key: &str) -> Option<&str> {
+        match self.values.get(key) {
+            Some(values) if values.len() == 1 => values.iter().next().map(|value| &value[..]),
+            _ => None,
+        }
+    }
+
+    // The single value of an attribute, parsed as the given type
+    pub fn parse<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get(key).and_then(|value| value.parse().ok())
+    }
+
+    // All values allowed for an attribute, or None if no caveat declared it
+    pub fn values(&self, key: &str) -> Option<&BTreeSet<String>> {
+        self.values.get(key)
+    }
+
+    pub fn allows(&self, key: &str, value: &str) -> bool {
+        self.values.get(key).is_some_and(|values| values.contains(value))
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<'_, String, BTreeSet<String>> {
+        self.values.iter()
+    }
+
+    // Narrow an attribute to the given values
+    pub fn declare<I>(&mut self, key: &str, values: I)
+        where I: IntoIterator,
+              I::Item: Into<String>
+    {
+        let values: BTreeSet<String> = values.into_iter().map(Into::into).collect();
+
+        match self.values.get_mut(key) {
+            Some(existing) => existing.retain(|value| values.contains(value)),
+            None => {
+                self.values.insert(key.to_owned(), values);
+            }
+        }
+    }
+
+    fn declare_predicate(&mut self, predicate: Predicate) {
+        match predicate {
+            Predicate::Eq(key, value) => self.declare(&key, Some(value)),
+            Predicate::In(key, values) => self.declare(&key, values),
+            _ => (),
+        }
+    }
+}
+
+// Verify a token and its discharges, collecting the attributes declared by
+// the caveats the verifier was satisfied by
+pub fn verify<T, V>(token: &T,
+                    key: &RootKey,
+                    verifier: V,
+                    discharges: &[T])
+                    -> result::Result<Attributes, VerificationError>
+    where T: Token,
+          V: CaveatVerifier
+{
+    let recorder = Recorder {
+        verifier,
+        attributes: RefCell::new(Attributes::new()),
+    };
+
+    token.verify_with_discharges_detailed(key, &recorder, discharges)?;
+
+    Ok(recorder.attributes.into_inner())
+}
+
+struct Recorder<V: CaveatVerifier> {
+    verifier: V,
+    attributes: RefCell<Attributes>,
+}
+
+impl<V: CaveatVerifier> CaveatVerifier for &Recorder<V> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        let outcome = self.verifier.check_first_party(caveat);
+
+        if outcome == Outcome::Satisfied {
+            if let Ok(predicate) = Predicate::parse(caveat) {
+                self.attributes.borrow_mut().declare_predicate(predicate);
+            }
+        }
+
+        outcome
+    }
+
+    fn check_third_party(&self, caveat: &[u8]) -> Outcome {
+        self.verifier.check_third_party(caveat)
+    }
+}
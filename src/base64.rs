@@ -0,0 +1,153 @@
+#[cfg(feature = "std")]
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use error::{Error, Result};
+use token::Write;
+
+// The URL-safe Base64 alphabet
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+// Number of encoded bytes buffered before they are written out
+const BUFFER_SIZE: usize = 512;
+
+// Length of the unpadded Base64 encoding of the given number of bytes
+pub fn encoded_len(length: usize) -> usize {
+    length / 3 * 4 + (length % 3 * 4).div_ceil(3)
+}
+
+// Encode as unpadded URL-safe Base64
+#[cfg(feature = "std")]
+pub fn encode(data: &[u8]) -> String {
+    let mut encoded = Vec::with_capacity(encoded_len(data.len()));
+
+    // Writing into a Vec never fails
+    let mut writer = Base64Writer::new(&mut encoded);
+    let _ = writer.write_all(data).and_then(|_| writer.finish());
+
+    encoded.into_iter().map(char::from).collect()
+}
+
+// Decode standard or URL-safe Base64, padded or not, skipping line breaks.
+// V1 tokens have always been read this leniently, as rustc_serialize did.
+pub fn decode(encoded: &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3 + 2);
+    let mut group: u32 = 0;
+    let mut group_len = 0;
+    let mut bytes = encoded.iter();
+
+    for &byte in bytes.by_ref() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'\r' | b'\n' => continue,
+            b'=' => break,
+            _ => return Err(Error::Base64),
+        };
+
+        group = group << 6 | value as u32;
+        group_len += 1;
+
+        if group_len == 4 {
+            decoded.extend_from_slice(&[(group >> 16) as u8, (group >> 8) as u8, group as u8]);
+            group = 0;
+            group_len = 0;
+        }
+    }
+
+    // Only more padding and line breaks may follow the first "="
+    if bytes.any(|&byte| byte != b'=' && byte != b'\r' && byte != b'\n') {
+        return Err(Error::Base64);
+    }
+
+    match group_len {
+        0 => (),
+        2 => decoded.push((group >> 4) as u8),
+        3 => decoded.extend_from_slice(&[(group >> 10) as u8, (group >> 2) as u8]),
+        _ => return Err(Error::Base64),
+    }
+
+    Ok(decoded)
+}
+
+// Encodes everything written to it as unpadded URL-safe Base64 and passes it
+// on to the wrapped writer. finish() must be called once everything has been
+// written, to encode the final partial group and flush the buffer.
+pub struct Base64Writer<'a, W: Write + 'a> {
+    inner: &'a mut W,
+    group: [u8; 3],
+    group_len: usize,
+    buffer: [u8; BUFFER_SIZE],
+    buffer_len: usize,
+}
+
+impl<'a, W: Write> Base64Writer<'a, W> {
+    pub fn new(inner: &'a mut W) -> Base64Writer<'a, W> {
+        Base64Writer {
+            inner,
+            group: [0; 3],
+            group_len: 0,
+            buffer: [0; BUFFER_SIZE],
+            buffer_len: 0,
+        }
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        if self.group_len > 0 {
+            let group_len = self.group_len;
+
+            for byte in &mut self.group[group_len..] {
+                *byte = 0;
+            }
+
+            self.encode_group(group_len + 1)?;
+        }
+
+        self.flush_buffer()
+    }
+
+    // Encode the current group, keeping only the first `output_len` symbols
+    fn encode_group(&mut self, output_len: usize) -> Result<()> {
+        if self.buffer_len + 4 > BUFFER_SIZE {
+            self.flush_buffer()?;
+        }
+
+        let group = (self.group[0] as usize) << 16 | (self.group[1] as usize) << 8 |
+                    self.group[2] as usize;
+        let symbols = [ALPHABET[group >> 18],
+                       ALPHABET[(group >> 12) & 0x3f],
+                       ALPHABET[(group >> 6) & 0x3f],
+                       ALPHABET[group & 0x3f]];
+
+        self.buffer[self.buffer_len..self.buffer_len + output_len]
+            .copy_from_slice(&symbols[..output_len]);
+        self.buffer_len += output_len;
+        self.group_len = 0;
+
+        Ok(())
+    }
+
+    fn flush_buffer(&mut self) -> Result<()> {
+        self.inner.write_all(&self.buffer[..self.buffer_len])?;
+        self.buffer_len = 0;
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> Write for Base64Writer<'a, W> {
+    fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        for &byte in data {
+            self.group[self.group_len] = byte;
+            self.group_len += 1;
+
+            if self.group_len == 3 {
+                self.encode_group(4)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,6 +1,32 @@
-use std::fmt;
+use alloc::borrow::ToOwned;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::str;
+#[cfg(feature = "std")]
+use std::time::SystemTime;
 
-pub type Predicate = Vec<u8>;
+use zeroize::Zeroize;
+
+use error::{Error, Result};
+#[cfg(feature = "std")]
+use expiry;
+
+// Separator between the values of an `in` predicate
+const LIST_SEPARATOR: char = ',';
+
+// A first-party caveat predicate of the form "key op value", e.g.
+// "account = 3735928559" or "action in read,write". Keys may not contain
+// whitespace, and the values of an `in` predicate may not contain commas.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Predicate {
+    Eq(String, String),
+    NotEq(String, String),
+    Lt(String, String),
+    Gt(String, String),
+    In(String, Vec<String>),
+    Prefix(String, String),
+}
 
 #[derive(Clone)]
 pub struct Caveat {
@@ -11,9 +37,9 @@ pub struct Caveat {
 }
 
 impl Caveat {
-    pub fn first_party(caveat_id: Predicate) -> Caveat {
+    pub fn first_party(caveat_id: Vec<u8>) -> Caveat {
         Caveat {
-            caveat_id: caveat_id,
+            caveat_id,
             caveat_key: None,
             verification_id: None,
             caveat_location: None,
@@ -25,17 +51,159 @@ impl Caveat {
                        caveat_location: Vec<u8>)
                        -> Caveat {
         Caveat {
-            caveat_id: caveat_id,
+            caveat_id,
             caveat_key: Some(caveat_key),
             verification_id: None,
             caveat_location: Some(caveat_location),
         }
     }
+
+    // Create a first-party caveat from the canonical form of a predicate
+    pub fn predicate(predicate: &Predicate) -> Result<Caveat> {
+        predicate.validate()?;
+        Ok(Caveat::first_party(predicate.to_string().into_bytes()))
+    }
+
+    pub fn eq(key: &str, value: &str) -> Result<Caveat> {
+        Caveat::predicate(&Predicate::Eq(key.to_owned(), value.to_owned()))
+    }
+
+    pub fn not_eq(key: &str, value: &str) -> Result<Caveat> {
+        Caveat::predicate(&Predicate::NotEq(key.to_owned(), value.to_owned()))
+    }
+
+    pub fn lt(key: &str, value: &str) -> Result<Caveat> {
+        Caveat::predicate(&Predicate::Lt(key.to_owned(), value.to_owned()))
+    }
+
+    pub fn gt(key: &str, value: &str) -> Result<Caveat> {
+        Caveat::predicate(&Predicate::Gt(key.to_owned(), value.to_owned()))
+    }
+
+    pub fn is_in<S: AsRef<str>>(key: &str, values: &[S]) -> Result<Caveat> {
+        let values = values.iter().map(|value| value.as_ref().to_owned()).collect();
+        Caveat::predicate(&Predicate::In(key.to_owned(), values))
+    }
+
+    pub fn prefix(key: &str, value: &str) -> Result<Caveat> {
+        Caveat::predicate(&Predicate::Prefix(key.to_owned(), value.to_owned()))
+    }
+
+    // Create a caveat that expires at the given time, to be checked by
+    // expiry::Expiry
+    #[cfg(feature = "std")]
+    pub fn expiry(time: SystemTime) -> Result<Caveat> {
+        Caveat::lt(expiry::EXPIRY_KEY, &expiry::format_timestamp(time)?)
+    }
+
+    pub fn is_third_party(&self) -> bool {
+        self.caveat_key.is_some() || self.verification_id.is_some()
+    }
+
+    // Parse the caveat id of a first-party caveat as a predicate
+    pub fn parse_predicate(&self) -> Result<Predicate> {
+        Predicate::parse(&self.caveat_id)
+    }
+}
+
+impl Predicate {
+    pub fn parse(predicate: &[u8]) -> Result<Predicate> {
+        let predicate = str::from_utf8(predicate).map_err(|_e| Error::MalformedPredicate)?;
+
+        let mut parts = predicate.splitn(3, ' ');
+        let key = parts.next().unwrap_or("").to_owned();
+        let op = parts.next().ok_or(Error::MalformedPredicate)?;
+        let value = parts.next().ok_or(Error::MalformedPredicate)?.to_owned();
+
+        let predicate = match op {
+            "=" => Predicate::Eq(key, value),
+            "!=" => Predicate::NotEq(key, value),
+            "<" => Predicate::Lt(key, value),
+            ">" => Predicate::Gt(key, value),
+            "in" => Predicate::In(key, value.split(LIST_SEPARATOR).map(str::to_owned).collect()),
+            "prefix" => Predicate::Prefix(key, value),
+            _ => return Err(Error::MalformedPredicate),
+        };
+
+        predicate.validate()?;
+        Ok(predicate)
+    }
+
+    pub fn key(&self) -> &str {
+        match *self {
+            Predicate::Eq(ref key, _) |
+            Predicate::NotEq(ref key, _) |
+            Predicate::Lt(ref key, _) |
+            Predicate::Gt(ref key, _) |
+            Predicate::In(ref key, _) |
+            Predicate::Prefix(ref key, _) => key,
+        }
+    }
+
+    // Check whether the actual value of the predicate's key satisfies it.
+    // `<` and `>` compare integers, and are never satisfied by anything else.
+    pub fn matches(&self, actual: &str) -> bool {
+        match *self {
+            Predicate::Eq(_, ref value) => actual == value,
+            Predicate::NotEq(_, ref value) => actual != value,
+            Predicate::Lt(_, ref value) => compare_integers(actual, value, |a, b| a < b),
+            Predicate::Gt(_, ref value) => compare_integers(actual, value, |a, b| a > b),
+            Predicate::In(_, ref values) => values.iter().any(|value| value == actual),
+            Predicate::Prefix(_, ref value) => actual.starts_with(&value[..]),
+        }
+    }
+
+    // Check the predicate survives a round trip through its canonical form
+    fn validate(&self) -> Result<()> {
+        let key = self.key();
+
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(Error::MalformedPredicate);
+        }
+
+        if let Predicate::In(_, ref values) = *self {
+            if values.is_empty() || values.iter().any(|value| value.contains(LIST_SEPARATOR)) {
+                return Err(Error::MalformedPredicate);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn compare_integers<F: Fn(i64, i64) -> bool>(actual: &str, expected: &str, compare: F) -> bool {
+    match (actual.parse(), expected.parse()) {
+        (Ok(actual), Ok(expected)) => compare(actual, expected),
+        _ => false,
+    }
+}
+
+// The canonical form of the predicate, as stored in caveats
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Predicate::Eq(ref key, ref value) => write!(f, "{} = {}", key, value),
+            Predicate::NotEq(ref key, ref value) => write!(f, "{} != {}", key, value),
+            Predicate::Lt(ref key, ref value) => write!(f, "{} < {}", key, value),
+            Predicate::Gt(ref key, ref value) => write!(f, "{} > {}", key, value),
+            Predicate::In(ref key, ref values) => {
+                write!(f, "{} in {}", key, values.join(&LIST_SEPARATOR.to_string()))
+            }
+            Predicate::Prefix(ref key, ref value) => write!(f, "{} prefix {}", key, value),
+        }
+    }
+}
+
+// The caveat key is the root key of the third party's discharge
+impl Drop for Caveat {
+    fn drop(&mut self) {
+        self.caveat_key.zeroize();
+    }
 }
 
 impl fmt::Display for Caveat {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let caveat_id = String::from_utf8(self.caveat_id.clone()).unwrap();
-        write!(f, "{}", &caveat_id)
+        // Caveat ids come from untrusted tokens, so may not be UTF-8
+        write!(f, "{}", String::from_utf8_lossy(&self.caveat_id))
     }
 }
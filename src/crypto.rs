@@ -0,0 +1,191 @@
+use alloc::vec::Vec;
+use core::ops::Deref;
+
+use zeroize::{Zeroize, Zeroizing};
+
+use super::KEY_GENERATOR;
+use caveat::Caveat;
+use error::{Error, Result};
+use rng::Rng;
+
+#[cfg(feature = "rust-crypto")]
+use rust_crypto::RustCrypto as Selected;
+#[cfg(not(feature = "rust-crypto"))]
+use sodium::Sodium as Selected;
+
+pub const TAGBYTES: usize = 32;
+
+// Sizes of the XSalsa20 nonce and Poly1305 tag of secretboxes
+pub const NONCEBYTES: usize = 24;
+const MACBYTES: usize = 16;
+
+pub type Tag = [u8; TAGBYTES];
+
+// The primitives used to build tokens. Every backend must compute the same
+// HMAC-SHA256 tags and XSalsa20-Poly1305 secretboxes as libsodium, or tokens
+// won't verify across them.
+pub trait Backend {
+    // HMAC-SHA256, with keys of any length
+    fn hmac(key: &[u8], data: &[u8]) -> Tag;
+
+    // Fill the buffer from the OS's cryptographically secure RNG
+    #[cfg(feature = "std")]
+    fn random(buffer: &mut [u8]);
+
+    // Encrypt with the given nonce, returning tag || ciphertext
+    fn seal(key: &Tag, nonce: &[u8; NONCEBYTES], plaintext: &[u8]) -> Vec<u8>;
+
+    // Decrypt a tag || ciphertext pair
+    fn open(key: &Tag, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>>;
+
+    // Constant-time comparison function
+    fn secure_eq(a: &[u8], b: &[u8]) -> bool;
+}
+
+// Key material that is wiped from memory when dropped. Besides derived keys,
+// this holds the signatures partway along a token's HMAC chain, since each
+// one is the key for the rest of the chain.
+pub struct SecretKey(Tag);
+
+impl SecretKey {
+    pub fn new(key: Tag) -> SecretKey {
+        SecretKey(key)
+    }
+
+    // Derive the personalized HMAC key used for a root or caveat key
+    pub fn derive(key: &[u8]) -> SecretKey {
+        SecretKey(hmac(KEY_GENERATOR, key))
+    }
+}
+
+impl Deref for SecretKey {
+    type Target = Tag;
+
+    fn deref(&self) -> &Tag {
+        &self.0
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+pub fn hmac(key: &[u8], data: &[u8]) -> Tag {
+    Selected::hmac(key, data)
+}
+
+// Random bytes from the OS's RNG
+#[cfg(feature = "std")]
+pub fn random(buffer: &mut [u8]) {
+    Selected::random(buffer)
+}
+
+// PBKDF2-HMAC-SHA256 (RFC 8018), producing a single block of output
+pub fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32) -> SecretKey {
+    let mut input = salt.to_vec();
+    input.extend_from_slice(&1u32.to_be_bytes());
+
+    let mut block = SecretKey(hmac(password, &input));
+    let mut result = SecretKey(*block);
+
+    for _ in 1..iterations {
+        block = SecretKey(hmac(password, &*block));
+
+        for (output, byte) in result.0.iter_mut().zip(block.iter()) {
+            *output ^= byte;
+        }
+    }
+
+    result
+}
+
+// HMAC(key, HMAC(key, data1) || HMAC(key, data2)), as used by libmacaroons
+pub fn hmac2(key: &Tag, data1: &[u8], data2: &[u8]) -> Tag {
+    let mut data = Zeroizing::new([0u8; 2 * TAGBYTES]);
+    data[..TAGBYTES].copy_from_slice(&hmac(key, data1));
+    data[TAGBYTES..].copy_from_slice(&hmac(key, data2));
+
+    hmac(key, &*data)
+}
+
+// Bind a discharge signature to the signature of the authorizing token, so
+// the discharge cannot be used alongside any other token
+pub fn bind(authorizing_tag: &Tag, discharge_tag: &Tag) -> Tag {
+    hmac2(&[0u8; TAGBYTES], authorizing_tag, discharge_tag)
+}
+
+// Chain a caveat onto the given signature, returning the new signature along
+// with the caveat as it should be stored in the token
+pub fn add_caveat<R: Rng>(tag: &Tag, caveat: &Caveat, rng: &mut R) -> Result<(Tag, Caveat)> {
+    let mut new_caveat = caveat.clone();
+
+    // The verification id carries the (derived) caveat key encrypted under the
+    // current signature, so that whoever verifies the token can recover it.
+    // The token has no further use for the key itself.
+    if let Some(key) = new_caveat.caveat_key.take().map(Zeroizing::new) {
+        new_caveat.verification_id = Some(encrypt(tag, &*SecretKey::derive(&key), rng)?);
+    }
+
+    Ok((chain_caveat(tag, &new_caveat), new_caveat))
+}
+
+// Chain a caveat as stored in a token onto the given signature. Note that
+// libmacaroons hashes the verification id before the caveat id.
+pub fn chain_caveat(tag: &Tag, caveat: &Caveat) -> Tag {
+    match caveat.verification_id {
+        Some(ref verification_id) => hmac2(tag, verification_id, &caveat.caveat_id),
+        None => hmac(tag, &caveat.caveat_id),
+    }
+}
+
+// Recompute the signature of a token from its derived root key
+pub fn signature(key: &Tag, identifier: &[u8], caveats: &[Caveat]) -> Tag {
+    let mut tag = SecretKey(hmac(key, identifier));
+
+    for caveat in caveats {
+        tag = SecretKey(chain_caveat(&tag, caveat));
+    }
+
+    *tag
+}
+
+// Recover the derived caveat key from a verification id
+pub fn decrypt_caveat_key(tag: &Tag, verification_id: &[u8]) -> Result<SecretKey> {
+    let plaintext = decrypt(tag, verification_id)?;
+
+    if plaintext.len() != TAGBYTES {
+        return Err(Error::Decryption);
+    }
+
+    let mut caveat_key = SecretKey([0u8; TAGBYTES]);
+    caveat_key.0.copy_from_slice(&plaintext);
+    Ok(caveat_key)
+}
+
+// Encrypt the plaintext with a random nonce, returning nonce || ciphertext
+pub fn encrypt<R: Rng>(key: &Tag, plaintext: &[u8], rng: &mut R) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCEBYTES];
+    rng.fill(&mut nonce)?;
+
+    let mut result = nonce.to_vec();
+    result.extend(Selected::seal(key, &nonce, plaintext));
+    Ok(result)
+}
+
+// Decrypt a nonce || ciphertext pair produced by encrypt. Plaintexts hold
+// keys, so they are wiped once dropped.
+pub fn decrypt(key: &Tag, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    if ciphertext.len() < NONCEBYTES + MACBYTES {
+        return Err(Error::Decryption);
+    }
+
+    let (nonce, ciphertext) = ciphertext.split_at(NONCEBYTES);
+    Selected::open(key, nonce, ciphertext).map(Zeroizing::new)
+}
+
+// Constant-time comparison function
+pub fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    Selected::secure_eq(a, b)
+}
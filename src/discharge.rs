@@ -0,0 +1,124 @@
+use alloc::vec::Vec;
+
+use zeroize::Zeroizing;
+
+use caveat::Caveat;
+use crypto;
+use error::{Error, Result};
+use key::RootKey;
+#[cfg(feature = "std")]
+use rng::OsRng;
+use rng::Rng;
+use token::Token;
+use v1::V1Token;
+
+// Length of the big-endian caveat key length prefix in an encoded caveat id
+const KEY_LENGTH_PREFIX: usize = 2;
+
+// The information a third party recovers from the caveat id of a third-party
+// caveat: the caveat root key to mint a discharge with, and the predicate it
+// must check before doing so
+pub struct ThirdPartyInfo {
+    pub caveat_id: Vec<u8>,
+    pub caveat_key: RootKey,
+    pub predicate: Vec<u8>,
+}
+
+impl ThirdPartyInfo {
+    // Mint a discharge token for the caveat. The third party should add any
+    // caveats of its own before handing it to the client.
+    pub fn discharge<T: Token>(&self, location: Option<Vec<u8>>) -> T {
+        T::new(&self.caveat_key, self.caveat_id.clone(), location)
+    }
+}
+
+// Create a third-party caveat whose caveat id carries the caveat key and
+// predicate, encrypted under a key shared with the third party
+#[cfg(feature = "std")]
+pub fn third_party_caveat(shared_key: &RootKey,
+                          caveat_key: Vec<u8>,
+                          predicate: Vec<u8>,
+                          caveat_location: Vec<u8>)
+                          -> Result<Caveat> {
+    third_party_caveat_with_rng(shared_key, caveat_key, predicate, caveat_location, &mut OsRng)
+}
+
+// As third_party_caveat, but with the caveat id's nonce from the given RNG
+pub fn third_party_caveat_with_rng<R: Rng>(shared_key: &RootKey,
+                                           caveat_key: Vec<u8>,
+                                           predicate: Vec<u8>,
+                                           caveat_location: Vec<u8>,
+                                           rng: &mut R)
+                                           -> Result<Caveat> {
+    if caveat_key.len() > u16::MAX as usize {
+        return Err(Error::PacketLength);
+    }
+
+    let capacity = KEY_LENGTH_PREFIX + caveat_key.len() + predicate.len();
+    let mut plaintext = Zeroizing::new(Vec::with_capacity(capacity));
+    plaintext.push((caveat_key.len() >> 8) as u8);
+    plaintext.push(caveat_key.len() as u8);
+    plaintext.extend_from_slice(&caveat_key);
+    plaintext.extend_from_slice(&predicate);
+
+    let caveat_id = crypto::encrypt(&crypto::SecretKey::derive(shared_key.as_bytes()),
+                                    &plaintext,
+                                    rng)?;
+
+    Ok(Caveat::third_party(caveat_key, caveat_id, caveat_location))
+}
+
+// Decode a caveat id created by third_party_caveat using the shared key
+pub fn decode_caveat_id(shared_key: &RootKey, caveat_id: &[u8]) -> Result<ThirdPartyInfo> {
+    let plaintext = crypto::decrypt(&crypto::SecretKey::derive(shared_key.as_bytes()),
+                                    caveat_id)?;
+
+    if plaintext.len() < KEY_LENGTH_PREFIX {
+        return Err(Error::MalformedPacket);
+    }
+
+    let key_length = ((plaintext[0] as usize) << 8) | plaintext[1] as usize;
+
+    if plaintext.len() < KEY_LENGTH_PREFIX + key_length {
+        return Err(Error::MalformedPacket);
+    }
+
+    let (caveat_key, predicate) = plaintext[KEY_LENGTH_PREFIX..].split_at(key_length);
+
+    Ok(ThirdPartyInfo {
+        caveat_id: caveat_id.to_vec(),
+        caveat_key: RootKey::from(caveat_key),
+        predicate: predicate.to_vec(),
+    })
+}
+
+// Obtain discharges for every third-party caveat in the token, including
+// third-party caveats found in the discharges themselves. The callback is
+// invoked once per distinct caveat id.
+pub fn collect_discharges<F>(token: &V1Token, mut get_discharge: F) -> Result<Vec<V1Token>>
+    where F: FnMut(&Caveat) -> Result<V1Token>
+{
+    let mut discharges: Vec<V1Token> = Vec::new();
+    let mut pending: Vec<Caveat> = token.third_party_caveats().cloned().collect();
+    let mut index = 0;
+
+    while index < pending.len() {
+        let caveat = pending[index].clone();
+        index += 1;
+
+        if discharges.iter().any(|discharge| discharge.identifier == caveat.caveat_id) {
+            continue;
+        }
+
+        let discharge = get_discharge(&caveat)?;
+
+        if discharge.identifier != caveat.caveat_id {
+            return Err(Error::InvalidDischarge);
+        }
+
+        pending.extend(discharge.third_party_caveats().cloned());
+        discharges.push(discharge);
+    }
+
+    Ok(discharges)
+}
@@ -1,41 +1,162 @@
-use std::{fmt, result};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::{fmt, result};
+#[cfg(feature = "std")]
 use std::error::Error as StdError;
 
+use verifier::Outcome;
+
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum Error {
     VerificationFailed,
     FirstPartyCaveatFailed,
     Base64,
     PacketLength,
+    PacketHeaderTruncated,
+    PacketTooShort,
+    PacketOverrun,
     SignatureLength,
     MalformedPacket,
     PacketOrdering,
     UnknownPacketType,
     MissingIdentifier,
     MissingSignature,
+    UnknownVersion,
+    Json,
+    Decryption,
+    InvalidDischarge,
+    MissingDischarge,
+    UnusedDischarge,
+    CyclicDischarge,
+    Io,
+    MalformedPredicate,
+    Timestamp,
+    MissingKeyId,
+    InvalidKeyId,
+    UnknownKeyId,
+    NoActiveKey,
+    KeyStore,
+    Iterations,
+    Random,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "{}", self.description())
-    }
-}
-
-impl StdError for Error {
-    fn description(&self) -> &str {
-        match *self {
+        let description = match *self {
             Error::VerificationFailed => "the token is inauthentic",
             Error::FirstPartyCaveatFailed => "a first-party caveat failed to verify",
             Error::Base64 => "unable to decode Base64",
             Error::PacketLength => "unable to decode packet length, or packet too long",
+            Error::PacketHeaderTruncated => "token ends in the middle of a packet header",
+            Error::PacketTooShort => "packet length shorter than the packet header",
+            Error::PacketOverrun => "packet length overruns the end of the token",
             Error::SignatureLength => "signature length incorrect",
             Error::MalformedPacket => "packet not properly structured",
             Error::PacketOrdering => "packet types are not in the right order",
             Error::UnknownPacketType => "packet found with unknown type",
             Error::MissingIdentifier => "no 'identifier' found at beginning of token",
             Error::MissingSignature => "no 'signature' found in token",
-        }
+            Error::UnknownVersion => "token has an unknown format version",
+            Error::Json => "unable to decode JSON, or JSON not properly structured",
+            Error::Decryption => "unable to decrypt ciphertext, or ciphertext inauthentic",
+            Error::InvalidDischarge => "discharge does not match its third-party caveat",
+            Error::MissingDischarge => "no discharge found for a third-party caveat",
+            Error::UnusedDischarge => "a discharge was provided but not used",
+            Error::CyclicDischarge => "a discharge was used more than once, or discharges form a cycle",
+            Error::Io => "unable to write the serialized token",
+            Error::MalformedPredicate => "caveat predicate not properly structured",
+            Error::Timestamp => "timestamp is not valid RFC 3339, or is out of range",
+            Error::MissingKeyId => "token identifier does not start with a key id",
+            Error::InvalidKeyId => "key id is malformed, or already in use",
+            Error::UnknownKeyId => "no root key found with the given key id",
+            Error::NoActiveKey => "no active root key to mint tokens with",
+            Error::KeyStore => "unable to read or write the key store, or it is malformed",
+            Error::Iterations => "key derivation needs at least one iteration",
+            Error::Random => "unable to generate random bytes",
+        };
+
+        write!(fmt, "{}", description)
     }
 }
 
+#[cfg(feature = "std")]
+impl StdError for Error {}
+
 pub type Result<T> = result::Result<T, Error>;
+
+pub type VerificationResult = result::Result<(), VerificationError>;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum FailureKind {
+    // The token (or a discharge) was not signed with the expected key, or
+    // was tampered with
+    Signature,
+    // A first-party caveat was rejected by the verifier
+    FirstParty,
+    // A third-party caveat was not discharged, or its discharge failed
+    ThirdParty,
+}
+
+// Why a token failed to verify, for diagnostics and logging. Use opaque() for
+// errors returned to clients, which should not learn which caveat failed.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct VerificationError {
+    pub kind: FailureKind,
+    // Index of the failing caveat within its token, if a caveat failed
+    pub caveat_index: Option<usize>,
+    // Predicate (caveat id) of the failing caveat
+    pub predicate: Option<Vec<u8>>,
+    // What the verifier said about the failing caveat
+    pub outcome: Option<Outcome>,
+    // Why the discharge for a third-party caveat failed to verify
+    pub discharge_error: Option<Box<VerificationError>>,
+    pub error: Error,
+}
+
+impl VerificationError {
+    pub fn opaque(&self) -> Error {
+        Error::VerificationFailed
+    }
+}
+
+impl From<VerificationError> for Error {
+    fn from(error: VerificationError) -> Error {
+        error.error
+    }
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            FailureKind::Signature => write!(fmt, "signature check failed")?,
+            FailureKind::FirstParty => write!(fmt, "first-party caveat failed")?,
+            FailureKind::ThirdParty => write!(fmt, "third-party caveat failed")?,
+        }
+
+        if let Some(index) = self.caveat_index {
+            write!(fmt, " at caveat {}", index)?;
+        }
+
+        if let Some(ref predicate) = self.predicate {
+            write!(fmt, " ({:?})", String::from_utf8_lossy(predicate))?;
+        }
+
+        write!(fmt, ": {}", self.error)?;
+
+        match self.outcome {
+            Some(Outcome::Violated(ref message)) => write!(fmt, ": {}", message)?,
+            Some(Outcome::Unrecognized) => write!(fmt, ": caveat not recognized")?,
+            _ => (),
+        }
+
+        if let Some(ref discharge_error) = self.discharge_error {
+            write!(fmt, "; discharge: {}", discharge_error)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl StdError for VerificationError {}
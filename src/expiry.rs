@@ -0,0 +1,234 @@
+use alloc::string::String;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use caveat::Predicate;
+use error::{Error, Result};
+use verifier::{CaveatVerifier, Outcome};
+
+// Key of expiry caveats, which take the form "time < 2030-01-01T00:00:00Z"
+pub const EXPIRY_KEY: &str = "time";
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+// Days between 0000-03-01 and 1970-01-01 in the proleptic Gregorian calendar
+const DAYS_TO_UNIX_EPOCH: i64 = 719_468;
+const DAYS_PER_ERA: i64 = 146_097;
+
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// A clock stuck at the given time, for tests
+pub struct FixedClock(pub SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+// Verifies expiry caveats against a clock. Any other caveat is unrecognized,
+// so it is left for other verifiers, either linked with this one or those of
+// a KeyedVerifier.
+pub struct Expiry<C: Clock = SystemClock>(pub C);
+
+impl Expiry<SystemClock> {
+    pub fn new() -> Expiry<SystemClock> {
+        Expiry(SystemClock)
+    }
+}
+
+impl Default for Expiry<SystemClock> {
+    fn default() -> Expiry<SystemClock> {
+        Expiry::new()
+    }
+}
+
+impl<C: Clock> Expiry<C> {
+    pub fn with_clock(clock: C) -> Expiry<C> {
+        Expiry(clock)
+    }
+}
+
+// Expiry caveats past their time, or with malformed timestamps, are violated
+impl<C: Clock> CaveatVerifier for Expiry<C> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        match Predicate::parse(caveat) {
+            Ok(Predicate::Lt(ref key, ref timestamp)) if key == EXPIRY_KEY => {
+                match parse_timestamp(timestamp) {
+                    Ok(expiry) if self.0.now() < expiry => Outcome::Satisfied,
+                    Ok(_) => Outcome::Violated(format!("expired at {}", timestamp)),
+                    Err(_) => Outcome::Violated(format!("malformed expiry time {:?}", timestamp)),
+                }
+            }
+            _ => Outcome::Unrecognized,
+        }
+    }
+}
+
+// Format a time as an RFC 3339 UTC timestamp with whole seconds, rounding
+// down. Only years 0000 to 9999 can be represented.
+pub fn format_timestamp(time: SystemTime) -> Result<String> {
+    let seconds = match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(error) => {
+            let duration = error.duration();
+            let whole = duration.as_secs() as i64;
+            if duration.subsec_nanos() > 0 { -whole - 1 } else { -whole }
+        }
+    };
+
+    let days = seconds.div_euclid(SECONDS_PER_DAY);
+    let time_of_day = seconds.rem_euclid(SECONDS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+
+    if !(0..=9999).contains(&year) {
+        return Err(Error::Timestamp);
+    }
+
+    Ok(format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+               year,
+               month,
+               day,
+               time_of_day / 3600,
+               time_of_day / 60 % 60,
+               time_of_day % 60))
+}
+
+// Parse an RFC 3339 timestamp, e.g. "2030-01-01T00:00:00Z" or
+// "2029-12-31T19:00:00.5-05:00"
+pub fn parse_timestamp(timestamp: &str) -> Result<SystemTime> {
+    let bytes = timestamp.as_bytes();
+
+    if bytes.len() < 20 || bytes[4] != b'-' || bytes[7] != b'-' ||
+       (bytes[10] != b'T' && bytes[10] != b't') || bytes[13] != b':' || bytes[16] != b':' {
+        return Err(Error::Timestamp);
+    }
+
+    let year = digits(&bytes[0..4])? as i64;
+    let month = digits(&bytes[5..7])?;
+    let day = digits(&bytes[8..10])?;
+    let hour = digits(&bytes[11..13])?;
+    let minute = digits(&bytes[14..16])?;
+    let second = digits(&bytes[17..19])?;
+
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) || hour > 23 ||
+       minute > 59 || second > 60 {
+        return Err(Error::Timestamp);
+    }
+
+    // Optional fractional seconds, of which nanoseconds are kept
+    let mut index = 19;
+    let mut nanos = 0;
+
+    if bytes[index] == b'.' {
+        index += 1;
+        let start = index;
+
+        while index < bytes.len() && bytes[index].is_ascii_digit() {
+            if index - start < 9 {
+                nanos = nanos * 10 + (bytes[index] - b'0') as u32;
+            }
+
+            index += 1;
+        }
+
+        if index == start {
+            return Err(Error::Timestamp);
+        }
+
+        for _ in index - start..9 {
+            nanos *= 10;
+        }
+    }
+
+    // The offset from UTC, either "Z" or of the form "+HH:MM"
+    let zone = &bytes[index..];
+
+    let offset = match zone.first() {
+        Some(&b'Z') | Some(&b'z') if zone.len() == 1 => 0,
+        Some(&sign) if (sign == b'+' || sign == b'-') && zone.len() == 6 && zone[3] == b':' => {
+            let offset_hours = digits(&zone[1..3])? as i64;
+            let offset_minutes = digits(&zone[4..6])? as i64;
+
+            if offset_hours > 23 || offset_minutes > 59 {
+                return Err(Error::Timestamp);
+            }
+
+            let offset = offset_hours * 3600 + offset_minutes * 60;
+            if sign == b'-' { -offset } else { offset }
+        }
+        _ => return Err(Error::Timestamp),
+    };
+
+    let seconds = days_from_civil(year, month, day) * SECONDS_PER_DAY + (hour * 3600) as i64 +
+                  (minute * 60) as i64 + second as i64 - offset;
+
+    let time = if seconds >= 0 {
+        UNIX_EPOCH.checked_add(Duration::new(seconds as u64, nanos))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::new((-seconds) as u64, 0))
+            .and_then(|time| time.checked_add(Duration::new(0, nanos)))
+    };
+
+    time.ok_or(Error::Timestamp)
+}
+
+fn digits(bytes: &[u8]) -> Result<u32> {
+    bytes.iter().try_fold(0, |value, &byte| {
+        if byte.is_ascii_digit() {
+            Ok(value * 10 + (byte - b'0') as u32)
+        } else {
+            Err(Error::Timestamp)
+        }
+    })
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since the Unix epoch of a date, using Howard Hinnant's algorithm
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * DAYS_PER_ERA + day_of_era - DAYS_TO_UNIX_EPOCH
+}
+
+// The inverse of days_from_civil
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + DAYS_TO_UNIX_EPOCH;
+    let era = days.div_euclid(DAYS_PER_ERA);
+    let day_of_era = days - era * DAYS_PER_ERA;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month as u32, day)
+}
@@ -0,0 +1,90 @@
+use alloc::vec::Vec;
+
+use base64;
+use error::{Error, Result};
+use token::Token;
+use v1::V1Token;
+use v2::V2Token;
+
+// First byte of a binary V2 token
+const V2_VERSION_BYTE: u8 = 2;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Format {
+    V1,
+    V2,
+    V2J,
+}
+
+// A token deserialized from any of the supported formats, tagged with the
+// format it was found in
+pub enum Macaroon {
+    V1(V1Token),
+    V2(V2Token),
+    V2J(V2Token),
+}
+
+impl Macaroon {
+    // Detect the format of a serialized token and deserialize it accordingly.
+    // Accepts V1 tokens in standard or URL-safe Base64 (padded or unpadded),
+    // V2 tokens as raw binary or Base64, and V2 JSON tokens (with std).
+    pub fn deserialize(macaroon: Vec<u8>) -> Result<Macaroon> {
+        // Binary tokens are passed through untouched, since their trailing
+        // signature bytes may well look like whitespace
+        if macaroon.first() == Some(&V2_VERSION_BYTE) {
+            return V2Token::deserialize(macaroon).map(Macaroon::V2);
+        }
+
+        let trimmed = trim(&macaroon);
+
+        match trimmed.first() {
+            None => return Err(Error::UnknownVersion),
+            #[cfg(feature = "std")]
+            Some(&b'{') => return V2Token::deserialize_json(trimmed.to_vec()).map(Macaroon::V2J),
+            // Reading JSON needs std
+            #[cfg(not(feature = "std"))]
+            Some(&b'{') => return Err(Error::Json),
+            _ => (),
+        }
+
+        let token_data = base64::decode(trimmed)?;
+
+        match token_data.first() {
+            Some(&V2_VERSION_BYTE) => V2Token::deserialize(token_data).map(Macaroon::V2),
+            // V1 tokens begin with the hex-encoded length of the first packet
+            Some(byte) if byte.is_ascii_hexdigit() => {
+                V1Token::deserialize_binary(&token_data).map(Macaroon::V1)
+            }
+            _ => Err(Error::UnknownVersion),
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        match *self {
+            Macaroon::V1(_) => Format::V1,
+            Macaroon::V2(_) => Format::V2,
+            Macaroon::V2J(_) => Format::V2J,
+        }
+    }
+
+    pub fn into_v1(self) -> V1Token {
+        match self {
+            Macaroon::V1(token) => token,
+            Macaroon::V2(token) | Macaroon::V2J(token) => V1Token::from(token),
+        }
+    }
+
+    pub fn into_v2(self) -> V2Token {
+        match self {
+            Macaroon::V1(token) => V2Token::from(token),
+            Macaroon::V2(token) | Macaroon::V2J(token) => token,
+        }
+    }
+}
+
+fn trim(data: &[u8]) -> &[u8] {
+    let start = data.iter().position(|byte| !byte.is_ascii_whitespace()).unwrap_or(data.len());
+    let end = data.iter().rposition(|byte| !byte.is_ascii_whitespace()).map_or(start, |i| i + 1);
+
+    &data[start..end]
+}
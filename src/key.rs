@@ -0,0 +1,144 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use zeroize::{Zeroize, Zeroizing};
+
+use crypto::{self, TAGBYTES};
+use error::{Error, Result};
+use rng::Rng;
+
+// Length of generated and derived root keys
+pub const ROOT_KEY_BYTES: usize = 32;
+
+// The secret a token is minted and verified with. Root keys are wiped from
+// memory when dropped, compared in constant time and never printed.
+#[derive(Clone)]
+pub struct RootKey(Zeroizing<Vec<u8>>);
+
+impl RootKey {
+    pub fn new(key: Vec<u8>) -> RootKey {
+        RootKey(Zeroizing::new(key))
+    }
+
+    // A random key from the OS's secure RNG
+    #[cfg(feature = "std")]
+    pub fn generate() -> RootKey {
+        let mut key = vec![0u8; ROOT_KEY_BYTES];
+        crypto::random(&mut key);
+        RootKey::new(key)
+    }
+
+    // A random key from the given RNG
+    pub fn generate_with_rng<R: Rng>(rng: &mut R) -> Result<RootKey> {
+        let mut key = RootKey::new(vec![0u8; ROOT_KEY_BYTES]);
+        rng.fill(&mut key.0)?;
+        Ok(key)
+    }
+
+    // Derive a key from a master key for the given context, e.g. a key id.
+    // Different contexts give unrelated keys.
+    pub fn derive(master_key: &RootKey, context: &[u8]) -> RootKey {
+        RootKey::new(crypto::hmac(master_key.as_bytes(), context).to_vec())
+    }
+
+    // Derive a key from a passphrase with PBKDF2-HMAC-SHA256. The salt should
+    // be random and stored alongside whatever uses the key, and the iteration
+    // count as high as can be afforded, and at least 1.
+    pub fn from_passphrase(passphrase: &[u8], salt: &[u8], iterations: u32) -> Result<RootKey> {
+        if iterations == 0 {
+            return Err(Error::Iterations);
+        }
+
+        Ok(RootKey::new(crypto::pbkdf2(passphrase, salt, iterations).to_vec()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for RootKey {
+    fn from(key: Vec<u8>) -> RootKey {
+        RootKey::new(key)
+    }
+}
+
+impl From<&[u8]> for RootKey {
+    fn from(key: &[u8]) -> RootKey {
+        RootKey::new(key.to_vec())
+    }
+}
+
+impl From<&str> for RootKey {
+    fn from(key: &str) -> RootKey {
+        RootKey::new(key.as_bytes().to_vec())
+    }
+}
+
+impl PartialEq for RootKey {
+    fn eq(&self, other: &RootKey) -> bool {
+        crypto::secure_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for RootKey {}
+
+impl fmt::Debug for RootKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("RootKey(<redacted>)")
+    }
+}
+
+// The signature of a token. Anyone holding it can add caveats to the token,
+// so like a root key it is wiped when dropped, compared in constant time and
+// never printed.
+#[derive(Clone)]
+pub struct Signature([u8; TAGBYTES]);
+
+impl Signature {
+    pub fn new(signature: [u8; TAGBYTES]) -> Signature {
+        Signature(signature)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; TAGBYTES] {
+        &self.0
+    }
+}
+
+impl From<[u8; TAGBYTES]> for Signature {
+    fn from(signature: [u8; TAGBYTES]) -> Signature {
+        Signature(signature)
+    }
+}
+
+impl PartialEq for Signature {
+    fn eq(&self, other: &Signature) -> bool {
+        crypto::secure_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Signature {}
+
+impl PartialEq<[u8; TAGBYTES]> for Signature {
+    fn eq(&self, other: &[u8; TAGBYTES]) -> bool {
+        crypto::secure_eq(&self.0, other)
+    }
+}
+
+impl PartialEq<Signature> for [u8; TAGBYTES] {
+    fn eq(&self, other: &Signature) -> bool {
+        crypto::secure_eq(self, &other.0)
+    }
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Signature(<redacted>)")
+    }
+}
+
+impl Drop for Signature {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
@@ -0,0 +1,416 @@
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str;
+#[cfg(feature = "std")]
+use core::mem;
+#[cfg(feature = "std")]
+use std::fs::{self, File, OpenOptions};
+#[cfg(feature = "std")]
+use std::io::{ErrorKind, Write};
+#[cfg(all(feature = "std", unix))]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "std")]
+use zeroize::Zeroizing;
+
+#[cfg(feature = "std")]
+use crypto;
+use error::{Error, Result};
+use key::RootKey;
+use rng::Rng;
+use token::Token;
+use verifier::CaveatVerifier;
+
+// Separates the key id from the rest of a token's identifier
+const KEY_ID_SEPARATOR: u8 = b':';
+
+#[cfg(feature = "std")]
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+// Root keys by id. Tokens are minted with the current key, with its id
+// prefixed to their identifier as "<key id>:<identifier>", so that the key
+// can be found again when they are verified. Keys are rotated by adding a
+// new current key and retiring the old one, which keeps verifying the tokens
+// minted with it until it is removed.
+pub trait RootKeyStore {
+    // The id and key new tokens are minted with
+    fn current_key(&self) -> Result<(String, RootKey)>;
+
+    // The key with the given id, if it may still verify tokens
+    fn key(&self, key_id: &str) -> Result<Option<RootKey>>;
+
+    // The key a token was minted with, given its identifier
+    fn root_key(&self, identifier: &[u8]) -> Result<RootKey> {
+        let (key_id, _) = split_identifier(identifier)?;
+        self.key(key_id)?.ok_or(Error::UnknownKeyId)
+    }
+
+    fn mint<T: Token>(&self, identifier: Vec<u8>, location: Option<Vec<u8>>) -> Result<T>
+        where Self: Sized
+    {
+        let (key_id, key) = self.current_key()?;
+
+        let mut prefixed = Vec::with_capacity(key_id.len() + 1 + identifier.len());
+        prefixed.extend_from_slice(key_id.as_bytes());
+        prefixed.push(KEY_ID_SEPARATOR);
+        prefixed.extend(identifier);
+
+        Ok(T::new(&key, prefixed, location))
+    }
+
+    fn verify<T, V>(&self, token: &T, verifier: V, discharges: &[T]) -> Result<()>
+        where Self: Sized,
+              T: Token,
+              V: CaveatVerifier
+    {
+        let key = self.root_key(token.identifier())?;
+        token.verify_with_discharges(&key, verifier, discharges)
+    }
+}
+
+// Split the identifier of a token minted by a RootKeyStore into the key id
+// and the identifier it was minted with
+pub fn split_identifier(identifier: &[u8]) -> Result<(&str, &[u8])> {
+    let separator = identifier.iter()
+        .position(|&byte| byte == KEY_ID_SEPARATOR)
+        .ok_or(Error::MissingKeyId)?;
+
+    let key_id = str::from_utf8(&identifier[..separator]).map_err(|_e| Error::MissingKeyId)?;
+
+    if !is_valid_key_id(key_id) {
+        return Err(Error::MissingKeyId);
+    }
+
+    Ok((key_id, &identifier[separator + 1..]))
+}
+
+// Key ids are limited to ASCII letters, digits, '-', '_' and '.', so they
+// can't contain the separator and can be stored as is
+fn is_valid_key_id(key_id: &str) -> bool {
+    !key_id.is_empty() &&
+    key_id.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte))
+}
+
+// A key held by a store. Retired keys verify tokens but don't mint them.
+#[derive(Debug, Clone)]
+pub struct StoredKey {
+    pub id: String,
+    pub key: RootKey,
+    pub retired: bool,
+}
+
+// Keys held in memory, in the order they were added. The most recently added
+// active key is the current one.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryKeyStore {
+    keys: Vec<StoredKey>,
+}
+
+impl MemoryKeyStore {
+    pub fn new() -> MemoryKeyStore {
+        MemoryKeyStore::default()
+    }
+
+    // Add an active key, which becomes the current one
+    pub fn insert(&mut self, key_id: &str, key: RootKey) -> Result<()> {
+        if !is_valid_key_id(key_id) || self.find(key_id).is_some() {
+            return Err(Error::InvalidKeyId);
+        }
+
+        self.keys.push(StoredKey {
+            id: key_id.to_owned(),
+            key,
+            retired: false,
+        });
+
+        Ok(())
+    }
+
+    // Add a random key as the current one
+    #[cfg(feature = "std")]
+    pub fn rotate(&mut self, key_id: &str) -> Result<()> {
+        self.insert(key_id, RootKey::generate())
+    }
+
+    // Add a key from the given RNG as the current one
+    pub fn rotate_with_rng<R: Rng>(&mut self, key_id: &str, rng: &mut R) -> Result<()> {
+        self.insert(key_id, RootKey::generate_with_rng(rng)?)
+    }
+
+    // Stop minting tokens with a key, while still verifying them
+    pub fn retire(&mut self, key_id: &str) -> Result<()> {
+        let index = self.find(key_id).ok_or(Error::UnknownKeyId)?;
+        self.keys[index].retired = true;
+        Ok(())
+    }
+
+    // Remove a key, so the tokens minted with it no longer verify
+    pub fn remove(&mut self, key_id: &str) -> Result<()> {
+        let index = self.find(key_id).ok_or(Error::UnknownKeyId)?;
+        self.keys.remove(index);
+        Ok(())
+    }
+
+    pub fn keys(&self) -> &[StoredKey] {
+        &self.keys
+    }
+
+    fn find(&self, key_id: &str) -> Option<usize> {
+        self.keys.iter().position(|stored| stored.id == key_id)
+    }
+}
+
+impl RootKeyStore for MemoryKeyStore {
+    fn current_key(&self) -> Result<(String, RootKey)> {
+        self.keys
+            .iter()
+            .rev()
+            .find(|stored| !stored.retired)
+            .map(|stored| (stored.id.clone(), stored.key.clone()))
+            .ok_or(Error::NoActiveKey)
+    }
+
+    fn key(&self, key_id: &str) -> Result<Option<RootKey>> {
+        Ok(self.find(key_id).map(|index| self.keys[index].key.clone()))
+    }
+}
+
+// Keys kept in a file, one per line as "<key id> <active|retired> <hex key>".
+// Changes are made while holding a lock on "<path>.lock", to the keys as they
+// are on disk, so concurrent changes from other instances are never lost.
+// Each change is written to a temporary file of its own which then replaces
+// the store, so readers never see a partial store. Other instances' changes
+// are picked up with reload(), or by making a change.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct FileKeyStore {
+    path: PathBuf,
+    keys: MemoryKeyStore,
+}
+
+#[cfg(feature = "std")]
+impl FileKeyStore {
+    // Open the store at the given path, which is created once a key is added
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileKeyStore> {
+        let mut store = FileKeyStore {
+            path: path.as_ref().to_path_buf(),
+            keys: MemoryKeyStore::new(),
+        };
+
+        store.reload()?;
+        Ok(store)
+    }
+
+    pub fn reload(&mut self) -> Result<()> {
+        self.keys = FileKeyStore::load(&self.path)?;
+        Ok(())
+    }
+
+    pub fn insert(&mut self, key_id: &str, key: RootKey) -> Result<()> {
+        self.update(|keys| keys.insert(key_id, key))
+    }
+
+    pub fn rotate(&mut self, key_id: &str) -> Result<()> {
+        self.update(|keys| keys.rotate(key_id))
+    }
+
+    pub fn retire(&mut self, key_id: &str) -> Result<()> {
+        self.update(|keys| keys.retire(key_id))
+    }
+
+    pub fn remove(&mut self, key_id: &str) -> Result<()> {
+        self.update(|keys| keys.remove(key_id))
+    }
+
+    pub fn keys(&self) -> &[StoredKey] {
+        self.keys.keys()
+    }
+
+    // Apply a change to the keys on disk and save it, leaving the store as it
+    // was if either fails. The lock file is left in place, since removing it
+    // would let another instance lock a file that is about to be replaced.
+    fn update<F>(&mut self, change: F) -> Result<()>
+        where F: FnOnce(&mut MemoryKeyStore) -> Result<()>
+    {
+        let lock = self.lock()?;
+
+        let mut keys = FileKeyStore::load(&self.path)?;
+        change(&mut keys)?;
+        self.save(&keys)?;
+        self.keys = keys;
+
+        lock.unlock().map_err(|_e| Error::KeyStore)
+    }
+
+    // Take the lock that serializes changes between instances, blocking until
+    // any other instance making a change is done
+    fn lock(&self) -> Result<File> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false);
+
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let lock = options.open(self.sibling_path(".lock")).map_err(|_e| Error::KeyStore)?;
+        lock.lock().map_err(|_e| Error::KeyStore)?;
+        Ok(lock)
+    }
+
+    fn load(path: &Path) -> Result<MemoryKeyStore> {
+        match fs::read_to_string(path) {
+            Ok(contents) => FileKeyStore::parse(&Zeroizing::new(contents)),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(MemoryKeyStore::new()),
+            Err(_) => Err(Error::KeyStore),
+        }
+    }
+
+    // A path next to the store's, with the given suffix
+    fn sibling_path(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    fn parse(contents: &str) -> Result<MemoryKeyStore> {
+        let mut keys = MemoryKeyStore::new();
+
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+
+            let (key_id, status, key) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(key_id), Some(status), Some(key)) => (key_id, status, key),
+                _ => return Err(Error::KeyStore),
+            };
+
+            let retired = match status {
+                "active" => false,
+                "retired" => true,
+                _ => return Err(Error::KeyStore),
+            };
+
+            if fields.next().is_some() {
+                return Err(Error::KeyStore);
+            }
+
+            let key = RootKey::new(decode_hex(key)?);
+            keys.insert(key_id, key).map_err(|_e| Error::KeyStore)?;
+
+            if retired {
+                keys.retire(key_id)?;
+            }
+        }
+
+        Ok(keys)
+    }
+
+    fn save(&self, keys: &MemoryKeyStore) -> Result<()> {
+        // Reserve enough up front that no copy of the keys is left behind
+        // when the buffer grows
+        let capacity = keys.keys()
+            .iter()
+            .map(|stored| stored.id.len() + 2 * stored.key.as_bytes().len() + 10)
+            .sum();
+        let mut contents = Zeroizing::new(String::with_capacity(capacity));
+
+        for stored in keys.keys() {
+            let status = if stored.retired { "retired" } else { "active" };
+
+            contents.push_str(&stored.id);
+            contents.push(' ');
+            contents.push_str(status);
+            contents.push(' ');
+            push_hex(&mut contents, stored.key.as_bytes());
+            contents.push('\n');
+        }
+
+        // A temporary file no other write can be using, even one that
+        // ignores the lock
+        let mut suffix = [0u8; 8];
+        crypto::random(&mut suffix);
+        let mut temporary_suffix = String::from(".");
+        push_hex(&mut temporary_suffix, &suffix);
+        temporary_suffix.push_str(".tmp");
+        let temporary_path = self.sibling_path(&temporary_suffix);
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+
+        // Only the owner may read the keys
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let mut file = options.open(&temporary_path).map_err(|_e| Error::KeyStore)?;
+        let result = file.write_all(contents.as_bytes())
+            .and_then(|_| file.sync_all())
+            .and_then(|_| fs::rename(&temporary_path, &self.path));
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temporary_path);
+            return Err(Error::KeyStore);
+        }
+
+        // The rename is only durable once the directory is, so a crash
+        // before then could still bring back the old keys
+        #[cfg(unix)]
+        FileKeyStore::sync_directory(&self.path)?;
+
+        Ok(())
+    }
+
+    // Flush the entries of the directory holding the path to disk
+    #[cfg(unix)]
+    fn sync_directory(path: &Path) -> Result<()> {
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        File::open(directory)
+            .and_then(|directory| directory.sync_all())
+            .map_err(|_e| Error::KeyStore)
+    }
+}
+
+#[cfg(feature = "std")]
+impl RootKeyStore for FileKeyStore {
+    fn current_key(&self) -> Result<(String, RootKey)> {
+        self.keys.current_key()
+    }
+
+    fn key(&self, key_id: &str) -> Result<Option<RootKey>> {
+        self.keys.key(key_id)
+    }
+}
+
+// Append the lowercase hex encoding of the bytes
+#[cfg(feature = "std")]
+fn push_hex(string: &mut String, bytes: &[u8]) {
+    for &byte in bytes {
+        string.push(HEX_DIGITS[(byte >> 4) as usize] as char);
+        string.push(HEX_DIGITS[(byte & 0xf) as usize] as char);
+    }
+}
+
+#[cfg(feature = "std")]
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.as_bytes();
+
+    if !hex.len().is_multiple_of(2) {
+        return Err(Error::KeyStore);
+    }
+
+    let digit = |byte: u8| (byte as char).to_digit(16).ok_or(Error::KeyStore);
+
+    // Allocated once, so no copy of the key is left behind by growing it,
+    // and wiped if the rest of it turns out to be malformed
+    let mut bytes = Zeroizing::new(Vec::with_capacity(hex.len() / 2));
+
+    for pair in hex.chunks(2) {
+        bytes.push((digit(pair[0])? << 4 | digit(pair[1])?) as u8);
+    }
+
+    Ok(mem::take(&mut *bytes))
+}
@@ -1,16 +1,66 @@
 #![crate_name = "macaroons"]
 #![crate_type = "lib"]
+#![no_std]
 
+#[cfg(not(any(feature = "sodium", feature = "rust-crypto")))]
+compile_error!("either the \"sodium\" or the \"rust-crypto\" feature must be enabled");
+
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "rust-crypto")]
+extern crate crypto_secretbox;
+#[cfg(feature = "rust-crypto")]
+extern crate hmac;
+#[cfg(feature = "rust-crypto")]
+extern crate sha2;
+#[cfg(feature = "rust-crypto")]
+extern crate subtle;
+#[cfg(feature = "sodium")]
 extern crate sodiumoxide;
-extern crate rustc_serialize;
+#[cfg(feature = "std")]
+extern crate serde;
+#[cfg(feature = "std")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "std")]
+extern crate serde_json;
+extern crate zeroize;
 
+pub mod async_verifier;
+pub mod attributes;
 pub mod caveat;
+pub mod discharge;
 pub mod error;
+#[cfg(feature = "std")]
+pub mod expiry;
+pub mod format;
+pub mod key;
+pub mod key_store;
+pub mod rng;
 pub mod token;
 pub mod verifier;
 
 pub mod v1;
+pub mod v2;
+#[cfg(feature = "std")]
+pub mod v2j;
+
+mod base64;
+mod crypto;
+#[cfg(feature = "rust-crypto")]
+mod rust_crypto;
+#[cfg(all(feature = "sodium", not(feature = "rust-crypto")))]
+mod sodium;
+mod verification;
+
+// Compiles the examples in the README as doctests
+#[cfg(doctest)]
+#[doc = include_str!("../README.md")]
+pub struct ReadmeDoctests;
 
 // Macaroons personalize the HMAC key using the string
 // "macaroons-key-generator" padded to 32-bytes with zeroes
-pub const KEY_GENERATOR: &'static [u8; 32] = b"macaroons-key-generator\0\0\0\0\0\0\0\0\0";
+pub const KEY_GENERATOR: &[u8; 32] = b"macaroons-key-generator\0\0\0\0\0\0\0\0\0";
@@ -0,0 +1,30 @@
+#[cfg(feature = "std")]
+use crypto;
+use error::Result;
+
+// A cryptographically secure source of random bytes, for root keys and the
+// nonces of verification ids and third-party caveat ids. With std, the OS's
+// RNG is used unless another is given. Without std there is none to fall
+// back on, so the functions needing random bytes take an Rng, such as one
+// backed by a hardware RNG.
+pub trait Rng {
+    fn fill(&mut self, buffer: &mut [u8]) -> Result<()>;
+}
+
+impl<R: Rng + ?Sized> Rng for &mut R {
+    fn fill(&mut self, buffer: &mut [u8]) -> Result<()> {
+        (**self).fill(buffer)
+    }
+}
+
+// The OS's secure RNG, as provided by the crypto backend
+#[cfg(feature = "std")]
+pub struct OsRng;
+
+#[cfg(feature = "std")]
+impl Rng for OsRng {
+    fn fill(&mut self, buffer: &mut [u8]) -> Result<()> {
+        crypto::random(buffer);
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use crypto_secretbox::aead::{Aead, KeyInit};
+#[cfg(feature = "std")]
+use crypto_secretbox::aead::rand_core::RngCore;
+#[cfg(feature = "std")]
+use crypto_secretbox::aead::OsRng;
+use crypto_secretbox::{Nonce, XSalsa20Poly1305};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crypto::{Backend, Tag, NONCEBYTES};
+use error::{Error, Result};
+
+// Crypto implemented in pure Rust by the RustCrypto crates
+pub struct RustCrypto;
+
+impl Backend for RustCrypto {
+    fn hmac(key: &[u8], data: &[u8]) -> Tag {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+            .expect("HMAC takes keys of any length");
+        mac.update(data);
+        mac.finalize().into_bytes().into()
+    }
+
+    #[cfg(feature = "std")]
+    fn random(buffer: &mut [u8]) {
+        OsRng.fill_bytes(buffer);
+    }
+
+    fn seal(key: &Tag, nonce: &[u8; NONCEBYTES], plaintext: &[u8]) -> Vec<u8> {
+        XSalsa20Poly1305::new(key.into())
+            .encrypt(&Nonce::from(*nonce), plaintext)
+            .expect("plaintext is too long to encrypt")
+    }
+
+    fn open(key: &Tag, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = <[u8; NONCEBYTES]>::try_from(nonce).map_err(|_e| Error::Decryption)?;
+
+        XSalsa20Poly1305::new(key.into())
+            .decrypt(&Nonce::from(nonce), ciphertext)
+            .map_err(|_e| Error::Decryption)
+    }
+
+    fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+        a.ct_eq(b).into()
+    }
+}
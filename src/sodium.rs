@@ -0,0 +1,37 @@
+use alloc::vec::Vec;
+
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::randombytes;
+use sodiumoxide::utils;
+
+use crypto::{Backend, Tag, NONCEBYTES};
+use error::{Error, Result};
+
+// Crypto backed by libsodium
+pub struct Sodium;
+
+impl Backend for Sodium {
+    fn hmac(key: &[u8], data: &[u8]) -> Tag {
+        let mut state = hmacsha256::State::init(key);
+        state.update(data);
+        state.finalize().0
+    }
+
+    fn random(buffer: &mut [u8]) {
+        randombytes::randombytes_into(buffer);
+    }
+
+    fn seal(key: &Tag, nonce: &[u8; NONCEBYTES], plaintext: &[u8]) -> Vec<u8> {
+        secretbox::seal(plaintext, &secretbox::Nonce(*nonce), &secretbox::Key(*key))
+    }
+
+    fn open(key: &Tag, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = secretbox::Nonce::from_slice(nonce).ok_or(Error::Decryption)?;
+        secretbox::open(ciphertext, &nonce, &secretbox::Key(*key)).map_err(|_e| Error::Decryption)
+    }
+
+    fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+        utils::memcmp(a, b)
+    }
+}
@@ -1,15 +1,130 @@
 
-use std::marker::Sized;
+use alloc::vec::Vec;
+use core::marker::Sized;
+use core::result;
+#[cfg(feature = "std")]
+use std::io;
 
+use async_verifier::{self, AsyncVerifier, Verification};
+use attributes::{self, Attributes};
 use caveat::Caveat;
-use error::Result;
-use verifier::Verifier;
+use error::{Error, Result, VerificationError, VerificationResult};
+use key::RootKey;
+#[cfg(feature = "std")]
+use rng::OsRng;
+use rng::Rng;
+use verifier::CaveatVerifier;
+
+// How a serialized token is encoded
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Encoding {
+    // Unpadded URL-safe Base64
+    Base64,
+    // The binary form of the token, as is
+    Raw,
+}
+
+// Where tokens are serialized to. With the "std" feature this is any
+// io::Write; without it, tokens can be written into a Vec.
+pub trait Write {
+    fn write_all(&mut self, data: &[u8]) -> Result<()>;
+}
+
+#[cfg(feature = "std")]
+impl<W: io::Write + ?Sized> Write for W {
+    fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        io::Write::write_all(self, data).map_err(|_e| Error::Io)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Write for Vec<u8> {
+    fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
 
 pub trait Token {
-    fn new(key: &[u8], identifier: Vec<u8>, location: Option<Vec<u8>>) -> Self;
+    fn new(key: &RootKey, identifier: Vec<u8>, location: Option<Vec<u8>>) -> Self;
+    fn identifier(&self) -> &[u8];
     fn deserialize(macaroon: Vec<u8>) -> Result<Self> where Self: Sized;
     fn serialize(&self) -> Result<Vec<u8>>;
-    fn add_caveat(&self, caveat: &Caveat) -> Self;
-    fn verify<V: Verifier>(&self, key: &[u8], verifier: V) -> Result<()>;
-    fn authenticate_without_verifying(&self, key: &[u8]) -> Result<()>;
+    fn serialized_len(&self, encoding: Encoding) -> Result<usize>;
+    fn serialize_into<W: Write>(&self, writer: &mut W, encoding: Encoding) -> Result<()>;
+    fn add_caveat_with_rng<R: Rng>(&self, caveat: &Caveat, rng: &mut R) -> Result<Self>
+        where Self: Sized;
+    fn prepare_for_request(&self, discharge: &Self) -> Self where Self: Sized;
+    fn verify_detailed<V: CaveatVerifier>(&self, key: &RootKey, verifier: V) -> VerificationResult;
+    fn verify_with_discharges_detailed<V: CaveatVerifier>(&self,
+                                                          key: &RootKey,
+                                                          verifier: V,
+                                                          discharges: &[Self])
+                                                          -> VerificationResult
+        where Self: Sized;
+    fn verify_discharge<V: CaveatVerifier>(&self,
+                                           authorizing: &Self,
+                                           caveat_key: &RootKey,
+                                           verifier: V)
+                                           -> Result<()>
+        where Self: Sized;
+    fn authenticate_without_verifying(&self, key: &RootKey) -> Result<()>;
+
+    // Add a caveat, drawing any nonce it needs from the OS's RNG
+    #[cfg(feature = "std")]
+    fn add_caveat(&self, caveat: &Caveat) -> Self
+        where Self: Sized
+    {
+        self.add_caveat_with_rng(caveat, &mut OsRng).expect("the OS's RNG failed")
+    }
+
+    fn verify<V: CaveatVerifier>(&self, key: &RootKey, verifier: V) -> Result<()> {
+        self.verify_detailed(key, verifier).map_err(Error::from)
+    }
+
+    fn verify_with_discharges<V: CaveatVerifier>(&self,
+                                                 key: &RootKey,
+                                                 verifier: V,
+                                                 discharges: &[Self])
+                                                 -> Result<()>
+        where Self: Sized
+    {
+        self.verify_with_discharges_detailed(key, verifier, discharges).map_err(Error::from)
+    }
+
+    // Verify, returning the attributes declared by the satisfied caveats
+    fn verify_attributes<V: CaveatVerifier>(&self,
+                                            key: &RootKey,
+                                            verifier: V,
+                                            discharges: &[Self])
+                                            -> result::Result<Attributes, VerificationError>
+        where Self: Sized
+    {
+        attributes::verify(self, key, verifier, discharges)
+    }
+
+    fn verify_async<'a, V: AsyncVerifier>(&'a self,
+                                          key: &RootKey,
+                                          verifier: &'a V)
+                                          -> Verification<'a>
+        where Self: Sized + Sync
+    {
+        async_verifier::verify(self, key, verifier)
+    }
+
+    fn verify_with_discharges_async<'a, V: AsyncVerifier>(&'a self,
+                                                          key: &RootKey,
+                                                          verifier: &'a V,
+                                                          discharges: &'a [Self])
+                                                          -> Verification<'a>
+        where Self: Sized + Sync
+    {
+        async_verifier::verify_with_discharges(self, key, verifier, discharges)
+    }
+
+    // Serialize onto the end of a buffer, reserving exactly the space needed
+    fn serialize_into_vec(&self, buffer: &mut Vec<u8>, encoding: Encoding) -> Result<()> {
+        buffer.reserve_exact(self.serialized_len(encoding)?);
+        self.serialize_into(buffer, encoding)
+    }
 }
@@ -1,107 +1,200 @@
-use std;
+use alloc::vec::Vec;
+use core::str;
 
-use rustc_serialize::base64::{self, FromBase64, ToBase64};
-
-use sodiumoxide::crypto::auth::hmacsha256::{self, Tag, Key, State, TAGBYTES};
-use sodiumoxide::crypto::secretbox;
-use sodiumoxide::utils;
-
-use super::KEY_GENERATOR;
+use base64::{self, Base64Writer};
 use caveat::Caveat;
-use error::{Error, Result};
-use token::Token;
-use verifier::Verifier;
+use crypto::TAGBYTES;
+use error::{Error, Result, VerificationResult};
+use key::{RootKey, Signature};
+use rng::Rng;
+use token::{Encoding, Token, Write};
+use verification::{self, Signed};
+use verifier::CaveatVerifier;
 
 const PACKET_PREFIX_LENGTH: usize = 4;
 const MAX_PACKET_LENGTH: usize = 65535;
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
 
 pub struct V1Token {
     pub identifier: Vec<u8>,
     pub location: Option<Vec<u8>>,
     pub caveats: Vec<Caveat>,
-    pub tag: [u8; TAGBYTES],
+    pub tag: Signature,
 }
 
-struct Packet {
-    pub id: Vec<u8>,
-    pub value: Vec<u8>,
+struct Packet<'a> {
+    pub id: &'a [u8],
+    pub value: &'a [u8],
     pub length: usize,
 }
 
+// A token parsed in place from its binary form, borrowing every field from
+// the decoded buffer instead of copying it
+pub struct TokenRef<'a> {
+    pub identifier: &'a [u8],
+    pub location: Option<&'a [u8]>,
+    pub tag: Signature,
+    caveat_data: &'a [u8],
+    caveat_count: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct CaveatRef<'a> {
+    pub caveat_id: &'a [u8],
+    pub verification_id: Option<&'a [u8]>,
+    pub caveat_location: Option<&'a [u8]>,
+}
+
+// Iterator over the caveats of a TokenRef
+pub struct Caveats<'a> {
+    data: &'a [u8],
+    index: usize,
+    remaining: usize,
+}
+
 impl V1Token {
-    fn packetize(result: &mut Vec<u8>, field: &str, value: &[u8]) -> Result<()> {
-        let field_bytes: Vec<u8> = Vec::from(field);
-        let packet_length = PACKET_PREFIX_LENGTH + field_bytes.len() + value.len() + 2;
+    fn packet_len(field: &str, value: &[u8]) -> Result<usize> {
+        let packet_length = PACKET_PREFIX_LENGTH + field.len() + value.len() + 2;
 
         if packet_length > MAX_PACKET_LENGTH {
             return Err(Error::PacketLength);
         }
 
-        let pkt_line = format!("{:04x}{} ", packet_length, field).into_bytes();
-        result.extend(pkt_line.into_iter());
-        result.extend(value.clone().into_iter());
-        result.push(b'\n');
+        Ok(packet_length)
+    }
 
-        Ok(())
+    fn write_packet<W: Write>(writer: &mut W, field: &str, value: &[u8]) -> Result<()> {
+        let packet_length = V1Token::packet_len(field, value)?;
+        let prefix = [HEX_DIGITS[packet_length >> 12],
+                      HEX_DIGITS[(packet_length >> 8) & 0xf],
+                      HEX_DIGITS[(packet_length >> 4) & 0xf],
+                      HEX_DIGITS[packet_length & 0xf]];
+
+        writer.write_all(&prefix)?;
+        writer.write_all(field.as_bytes())?;
+        writer.write_all(b" ")?;
+        writer.write_all(value)?;
+        writer.write_all(b"\n")
     }
 
-    fn depacketize(data: &[u8], index: usize) -> Result<Packet> {
-        let length_str = try!(std::str::from_utf8(&data[index..index + PACKET_PREFIX_LENGTH])
-            .map_err(|_e| Error::PacketLength));
+    // Call the given function with the field and value of every packet of the
+    // serialized token, in order
+    fn visit_packets<F>(&self, mut visit: F) -> Result<()>
+        where F: FnMut(&str, &[u8]) -> Result<()>
+    {
+        if let Some(ref location) = self.location {
+            visit("location", location)?;
+        }
 
-        let packet_length = try!(usize::from_str_radix(length_str, 16)
-            .map_err(|_e| Error::PacketLength));
+        visit("identifier", &self.identifier)?;
 
-        let mut packet_bytes = data[index + PACKET_PREFIX_LENGTH..index + packet_length].to_vec();
+        for caveat in &self.caveats {
+            visit("cid", &caveat.caveat_id)?;
 
-        let pos = try!(packet_bytes.iter()
-            .position(|&byte| byte == b' ')
-            .ok_or(Error::MalformedPacket));
+            if let Some(ref verification_id) = caveat.verification_id {
+                visit("vid", verification_id)?;
+            }
+
+            if let Some(ref caveat_location) = caveat.caveat_location {
+                visit("cl", caveat_location)?;
+            }
+        }
+
+        visit("signature", self.tag.as_bytes())
+    }
+
+    // Length of the binary form of the token, checking every packet fits
+    fn binary_len(&self) -> Result<usize> {
+        let mut length = 0;
+
+        self.visit_packets(|field, value| {
+            length += V1Token::packet_len(field, value)?;
+            Ok(())
+        })?;
+
+        Ok(length)
+    }
+
+    fn write_binary<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.visit_packets(|field, value| V1Token::write_packet(writer, field, value))
+    }
+
+    fn depacketize(data: &[u8], index: usize) -> Result<Packet<'_>> {
+        let remaining = data.get(index..).unwrap_or(&[]);
+
+        if remaining.len() < PACKET_PREFIX_LENGTH {
+            return Err(Error::PacketHeaderTruncated);
+        }
+
+        let length_bytes = &remaining[..PACKET_PREFIX_LENGTH];
 
-        let (id, value_arr) = packet_bytes.split_at_mut(pos);
-        let mut value = value_arr.to_vec();
-        value.remove(0);
+        // from_str_radix would otherwise accept a leading '+'
+        if !length_bytes.iter().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(Error::PacketLength);
+        }
+
+        let length_str = str::from_utf8(length_bytes).map_err(|_e| Error::PacketLength)?;
+
+        let packet_length = usize::from_str_radix(length_str, 16)
+            .map_err(|_e| Error::PacketLength)?;
 
-        if try!(value.pop().ok_or(Error::MalformedPacket)) != b'\n' {
-            return Err(Error::MalformedPacket);
+        // Packets must at least hold the header, the separating space and the
+        // trailing newline
+        if packet_length < PACKET_PREFIX_LENGTH + 2 {
+            return Err(Error::PacketTooShort);
         }
 
+        if packet_length > remaining.len() {
+            return Err(Error::PacketOverrun);
+        }
+
+        let packet_bytes = &remaining[PACKET_PREFIX_LENGTH..packet_length];
+
+        let pos = packet_bytes.iter()
+            .position(|&byte| byte == b' ')
+            .ok_or(Error::MalformedPacket)?;
+
+        let (id, value) = (&packet_bytes[..pos], &packet_bytes[pos + 1..]);
+
+        let value = match value.split_last() {
+            Some((&b'\n', value)) => value,
+            _ => return Err(Error::MalformedPacket),
+        };
+
         Ok(Packet {
-            id: id.to_vec(),
-            value: value,
+            id,
+            value,
             length: packet_length,
         })
     }
-}
 
-impl Token for V1Token {
-    fn new(key: &[u8], identifier: Vec<u8>, location: Option<Vec<u8>>) -> V1Token {
-        let Tag(personalized_key) = hmacsha256::authenticate(&key, &Key(*KEY_GENERATOR));
-        let Tag(tag) = hmacsha256::authenticate(&identifier, &Key(personalized_key));
+    pub fn third_party_caveats(&self) -> impl Iterator<Item = &Caveat> {
+        self.caveats.iter().filter(|caveat| caveat.is_third_party())
+    }
 
-        V1Token {
-            location: location,
-            identifier: identifier,
-            caveats: Vec::new(),
-            tag: tag,
-        }
+    // Parse a token from its binary (i.e. not Base64-encoded) form
+    pub fn deserialize_binary(token_data: &[u8]) -> Result<V1Token> {
+        TokenRef::parse(token_data).map(|token| token.into_owned())
     }
+}
 
-    fn deserialize(macaroon: Vec<u8>) -> Result<V1Token> {
-        let token_data = try!(macaroon.from_base64().map_err(|_e| Error::Base64));
+impl<'a> TokenRef<'a> {
+    // Parse a token from its binary (i.e. not Base64-encoded) form. The whole
+    // token is validated up front, so iterating its caveats cannot fail.
+    pub fn parse(token_data: &'a [u8]) -> Result<TokenRef<'a>> {
         let mut index: usize = 0;
 
         // Parse the (optional location and) identifier packets
-        let packet1 = try!(V1Token::depacketize(&token_data, index));
+        let packet1 = V1Token::depacketize(token_data, index)?;
         index += packet1.length;
 
-        let (identifier, location) = match &packet1.id[..] {
+        let (identifier, location) = match packet1.id {
             b"identifier" => (packet1.value, None),
             b"location" => {
-                let packet2 = try!(V1Token::depacketize(&token_data, index));
+                let packet2 = V1Token::depacketize(token_data, index)?;
                 index += packet2.length;
 
-                if &packet2.id[..] != b"identifier" {
+                if packet2.id != b"identifier" {
                     return Err(Error::MissingIdentifier);
                 }
 
@@ -110,43 +203,43 @@ impl Token for V1Token {
             _ => return Err(Error::MissingIdentifier),
         };
 
-        let mut caveats: Vec<Caveat> = Vec::new();
-        let mut tag: Option<[u8; TAGBYTES]> = None;
+        let caveats_start = index;
+        let mut caveat_count = 0;
+        let mut has_verification_id = false;
+        let mut has_caveat_location = false;
 
-        // Parse caveats
-        while index < token_data.len() {
-            let packet = try!(V1Token::depacketize(&token_data, index));
+        // Check the caveat packets, up to the signature. Each caveat has at
+        // most one verification id and one location.
+        loop {
+            if index >= token_data.len() {
+                return Err(Error::MissingSignature);
+            }
 
-            index += packet.length;
+            let packet = V1Token::depacketize(token_data, index)?;
+
+            match packet.id {
+                b"cid" => {
+                    caveat_count += 1;
+                    has_verification_id = false;
+                    has_caveat_location = false;
+                }
+                b"vid" => {
+                    if caveat_count == 0 || has_verification_id {
+                        return Err(Error::PacketOrdering);
+                    }
 
-            match &packet.id[..] {
-                b"cid" => caveats.push(Caveat::first_party(packet.value)),
-                b"vid" | b"cl" => {
-                    match caveats.pop() {
-                        Some(caveat) => {
-                            let caveat_id = caveat.caveat_id;
-                            let mut verification_id = caveat.verification_id;
-                            let mut caveat_location = caveat.caveat_location;
-
-                            match &packet.id[..] {
-                                b"vid" => verification_id = Some(packet.value),
-                                b"cl" => caveat_location = Some(packet.value),
-                                _ => return Err(Error::PacketOrdering),
-                            }
-
-                            caveats.push(Caveat {
-                                caveat_id: caveat_id,
-                                caveat_key: None,
-                                verification_id: verification_id,
-                                caveat_location: caveat_location,
-                            })
-                        }
-                        None => return Err(Error::PacketOrdering),
+                    has_verification_id = true;
+                }
+                b"cl" => {
+                    if caveat_count == 0 || has_caveat_location {
+                        return Err(Error::PacketOrdering);
                     }
+
+                    has_caveat_location = true;
                 }
                 b"signature" => {
                     // Make sure signature is the last packet
-                    if index != token_data.len() {
+                    if index + packet.length != token_data.len() {
                         return Err(Error::PacketOrdering);
                     }
 
@@ -154,134 +247,198 @@ impl Token for V1Token {
                         return Err(Error::SignatureLength);
                     }
 
-                    let mut signature_bytes = [0u8; TAGBYTES];
-                    signature_bytes.copy_from_slice(&packet.value);
+                    let mut tag = [0u8; TAGBYTES];
+                    tag.copy_from_slice(packet.value);
 
-                    tag = Some(signature_bytes);
+                    return Ok(TokenRef {
+                        identifier,
+                        location,
+                        tag: Signature::new(tag),
+                        caveat_data: &token_data[caveats_start..index],
+                        caveat_count,
+                    });
                 }
                 _ => return Err(Error::UnknownPacketType),
             }
-        }
 
-        if tag == None {
-            return Err(Error::MissingSignature);
+            index += packet.length;
         }
+    }
 
-        let token = V1Token {
-            identifier: identifier,
-            location: location,
-            caveats: caveats,
-            tag: tag.unwrap(),
-        };
+    pub fn caveats(&self) -> Caveats<'a> {
+        Caveats {
+            data: self.caveat_data,
+            index: 0,
+            remaining: self.caveat_count,
+        }
+    }
 
-        Ok(token)
+    pub fn into_owned(self) -> V1Token {
+        V1Token {
+            identifier: self.identifier.to_vec(),
+            location: self.location.map(|location| location.to_vec()),
+            caveats: self.caveats().map(|caveat| caveat.into_owned()).collect(),
+            tag: self.tag,
+        }
     }
+}
 
-    fn serialize(&self) -> Result<Vec<u8>> {
-        // TODO: estimate capacity and use Vec::with_capacity
-        let mut result: Vec<u8> = Vec::new();
+impl<'a> CaveatRef<'a> {
+    pub fn is_third_party(&self) -> bool {
+        self.verification_id.is_some()
+    }
 
-        if self.location.is_some() {
-            try!(V1Token::packetize(&mut result, "location", &self.location.clone().unwrap()));
+    pub fn into_owned(self) -> Caveat {
+        Caveat {
+            caveat_id: self.caveat_id.to_vec(),
+            caveat_key: None,
+            verification_id: self.verification_id.map(|vid| vid.to_vec()),
+            caveat_location: self.caveat_location.map(|location| location.to_vec()),
         }
+    }
+}
 
-        try!(V1Token::packetize(&mut result, "identifier", &self.identifier));
+impl<'a> Iterator for Caveats<'a> {
+    type Item = CaveatRef<'a>;
 
-        for caveat in &self.caveats {
-            try!(V1Token::packetize(&mut result, "cid", &caveat.caveat_id));
+    fn next(&mut self) -> Option<CaveatRef<'a>> {
+        // The packets were validated by TokenRef::parse, so the only error
+        // here is running out of them
+        let packet = V1Token::depacketize(self.data, self.index).ok()?;
+        self.index += packet.length;
+        self.remaining -= 1;
 
-            if caveat.verification_id.is_some() {
-                try!(V1Token::packetize(&mut result,
-                                        "vid",
-                                        &caveat.verification_id.clone().unwrap()));
-            }
+        let mut caveat = CaveatRef {
+            caveat_id: packet.value,
+            verification_id: None,
+            caveat_location: None,
+        };
 
-            if caveat.caveat_location.is_some() {
-                try!(V1Token::packetize(&mut result,
-                                        "cl",
-                                        &caveat.caveat_location.clone().unwrap()));
+        // Attach any vid and cl packets following the cid
+        while let Ok(packet) = V1Token::depacketize(self.data, self.index) {
+            match packet.id {
+                b"vid" => caveat.verification_id = Some(packet.value),
+                b"cl" => caveat.caveat_location = Some(packet.value),
+                _ => break,
             }
-        }
 
-        try!(V1Token::packetize(&mut result, "signature", &self.tag.to_vec()));
+            self.index += packet.length;
+        }
 
-        Ok(result.to_base64(base64::URL_SAFE).into_bytes())
+        Some(caveat)
     }
 
-    fn add_caveat(&self, caveat: &Caveat) -> V1Token {
-        let key_bytes = self.tag;
-        let mut new_caveats = self.caveats.to_vec();
-
-        let new_tag = match caveat.caveat_key {
-            Some(ref key) => {
-                let Tag(personalized_key) = hmacsha256::authenticate(&key, &Key(*KEY_GENERATOR));
-                let nonce = secretbox::gen_nonce();
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
 
-                let mut new_caveat = caveat.clone();
-                let verification_id =
-                    secretbox::seal(&key_bytes,
-                                    &nonce,
-                                    &secretbox::xsalsa20poly1305::Key(personalized_key));
+impl<'a> ExactSizeIterator for Caveats<'a> {}
 
-                let mut caveat_authenticator = State::init(&key_bytes);
+impl Token for V1Token {
+    fn new(key: &RootKey, identifier: Vec<u8>, location: Option<Vec<u8>>) -> V1Token {
+        verification::mint(key, identifier, location)
+    }
 
-                let Tag(caveat_id_tag) = hmacsha256::authenticate(&new_caveat.caveat_id,
-                                                                  &Key(key_bytes));
-                caveat_authenticator.update(&caveat_id_tag);
+    fn identifier(&self) -> &[u8] {
+        &self.identifier
+    }
 
-                let Tag(verification_id_tag) = hmacsha256::authenticate(&verification_id,
-                                                                        &Key(key_bytes));
-                caveat_authenticator.update(&verification_id_tag);
+    fn deserialize(macaroon: Vec<u8>) -> Result<V1Token> {
+        let token_data = base64::decode(&macaroon)?;
+        V1Token::deserialize_binary(&token_data)
+    }
 
-                new_caveat.verification_id = Some(verification_id);
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut result: Vec<u8> = Vec::new();
+        self.serialize_into_vec(&mut result, Encoding::Base64)?;
+        Ok(result)
+    }
 
-                new_caveats.push(new_caveat);
-                caveat_authenticator.finalize()
-            }
-            None => {
-                new_caveats.push(caveat.clone());
-                hmacsha256::authenticate(&caveat.caveat_id, &Key(key_bytes))
-            }
-        };
+    fn serialized_len(&self, encoding: Encoding) -> Result<usize> {
+        let length = self.binary_len()?;
 
-        V1Token {
-            identifier: self.identifier.clone(),
-            location: self.location.clone(),
-            caveats: new_caveats,
-            tag: new_tag.0,
+        match encoding {
+            Encoding::Base64 => Ok(base64::encoded_len(length)),
+            Encoding::Raw => Ok(length),
         }
     }
 
-    fn verify<V: Verifier>(&self, key: &[u8], verifier: V) -> Result<()> {
-        try!(self.authenticate_without_verifying(&key));
+    fn serialize_into<W: Write>(&self, writer: &mut W, encoding: Encoding) -> Result<()> {
+        // Check every packet fits before writing anything
+        self.binary_len()?;
 
-        for caveat in &self.caveats {
-            if caveat.verification_id == None {
-                if !verifier.verify_first_party(&caveat.caveat_id) {
-                    return Err(Error::VerificationFailed);
-                }
-            } else {
-                if !verifier.verify_third_party(&caveat.caveat_id) {
-                    return Err(Error::VerificationFailed);
-                }
+        match encoding {
+            Encoding::Base64 => {
+                let mut base64_writer = Base64Writer::new(writer);
+                self.write_binary(&mut base64_writer)?;
+                base64_writer.finish()
             }
+            Encoding::Raw => self.write_binary(writer),
         }
+    }
 
-        Ok(())
+    fn add_caveat_with_rng<R: Rng>(&self, caveat: &Caveat, rng: &mut R) -> Result<V1Token> {
+        verification::add_caveat(self, caveat, rng)
     }
 
-    fn authenticate_without_verifying(&self, key: &[u8]) -> Result<()> {
-        let mut verify_token = V1Token::new(&key, self.identifier.clone(), self.location.clone());
+    fn prepare_for_request(&self, discharge: &V1Token) -> V1Token {
+        verification::prepare_for_request(self, discharge)
+    }
 
-        for caveat in &self.caveats {
-            verify_token = verify_token.add_caveat(&caveat)
-        }
+    fn verify_detailed<V: CaveatVerifier>(&self, key: &RootKey, verifier: V) -> VerificationResult {
+        verification::verify(self, key, &verifier)
+    }
 
-        // Constant-time comparison function
-        if utils::memcmp(&verify_token.tag, &self.tag) {
-            Ok(())
-        } else {
-            Err(Error::VerificationFailed)
+    fn verify_with_discharges_detailed<V: CaveatVerifier>(&self,
+                                                          key: &RootKey,
+                                                          verifier: V,
+                                                          discharges: &[V1Token])
+                                                          -> VerificationResult {
+        verification::verify_with_discharges(self, key, &verifier, discharges)
+    }
+
+    fn verify_discharge<V: CaveatVerifier>(&self,
+                                           authorizing: &V1Token,
+                                           caveat_key: &RootKey,
+                                           verifier: V)
+                                           -> Result<()> {
+        verification::verify_discharge(self, authorizing, caveat_key, &verifier)
+            .map_err(Error::from)
+    }
+
+    fn authenticate_without_verifying(&self, key: &RootKey) -> Result<()> {
+        verification::authenticate(self, key)
+    }
+}
+
+impl Signed for V1Token {
+    fn from_parts(identifier: Vec<u8>,
+                  location: Option<Vec<u8>>,
+                  caveats: Vec<Caveat>,
+                  tag: Signature)
+                  -> V1Token {
+        V1Token {
+            identifier,
+            location,
+            caveats,
+            tag,
         }
     }
+
+    fn identifier(&self) -> &[u8] {
+        &self.identifier
+    }
+
+    fn location(&self) -> Option<&[u8]> {
+        self.location.as_ref().map(|location| &location[..])
+    }
+
+    fn caveats(&self) -> &[Caveat] {
+        &self.caveats
+    }
+
+    fn tag(&self) -> &[u8; TAGBYTES] {
+        self.tag.as_bytes()
+    }
 }
@@ -0,0 +1,397 @@
+use alloc::vec::Vec;
+
+use base64::{self, Base64Writer};
+use caveat::Caveat;
+use crypto::TAGBYTES;
+use error::{Error, Result, VerificationResult};
+use key::{RootKey, Signature};
+use rng::Rng;
+use token::{Encoding, Token, Write};
+use verification::{self, Signed};
+use v1::V1Token;
+use verifier::CaveatVerifier;
+
+const VERSION: u8 = 2;
+
+// Field types defined by the V2 binary format
+const FIELD_EOS: u8 = 0;
+const FIELD_LOCATION: u8 = 1;
+const FIELD_IDENTIFIER: u8 = 2;
+const FIELD_VID: u8 = 4;
+const FIELD_SIGNATURE: u8 = 6;
+
+pub struct V2Token {
+    pub identifier: Vec<u8>,
+    pub location: Option<Vec<u8>>,
+    pub caveats: Vec<Caveat>,
+    pub tag: Signature,
+}
+
+struct Field<'a> {
+    pub field_type: u8,
+    pub data: &'a [u8],
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, index: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.index >= self.data.len()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.index).cloned()
+    }
+
+    fn read_byte(&mut self) -> Result<u8> {
+        let byte = self.peek().ok_or(Error::MalformedPacket)?;
+        self.index += 1;
+        Ok(byte)
+    }
+
+    fn read_varint(&mut self) -> Result<usize> {
+        let mut value: u64 = 0;
+
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+
+            if byte & 0x80 == 0 {
+                if value > usize::MAX as u64 {
+                    return Err(Error::PacketLength);
+                }
+
+                return Ok(value as usize);
+            }
+        }
+
+        Err(Error::PacketLength)
+    }
+
+    fn read_field(&mut self) -> Result<Field<'a>> {
+        let field_type = self.read_byte()?;
+
+        if field_type == FIELD_EOS {
+            return Ok(Field {
+                field_type,
+                data: &[],
+            });
+        }
+
+        let length = self.read_varint()?;
+
+        if length > self.data.len() - self.index {
+            return Err(Error::PacketLength);
+        }
+
+        let data = &self.data[self.index..self.index + length];
+        self.index += length;
+
+        Ok(Field { field_type, data })
+    }
+
+    // Read an optional field of the given type, leaving the reader untouched
+    // if the next field is of a different type
+    fn read_optional(&mut self, field_type: u8) -> Result<Option<&'a [u8]>> {
+        if self.peek() != Some(field_type) {
+            return Ok(None);
+        }
+
+        Ok(Some(self.read_field()?.data))
+    }
+
+    fn read_eos(&mut self) -> Result<()> {
+        match self.read_field()?.field_type {
+            FIELD_EOS => Ok(()),
+            FIELD_LOCATION | FIELD_IDENTIFIER | FIELD_VID | FIELD_SIGNATURE => {
+                Err(Error::PacketOrdering)
+            }
+            _ => Err(Error::UnknownPacketType),
+        }
+    }
+}
+
+impl V2Token {
+    fn varint_len(mut value: usize) -> usize {
+        let mut length = 1;
+
+        while value >= 0x80 {
+            length += 1;
+            value >>= 7;
+        }
+
+        length
+    }
+
+    fn field_len(field_type: u8, data: &[u8]) -> usize {
+        if field_type == FIELD_EOS {
+            return 1;
+        }
+
+        1 + V2Token::varint_len(data.len()) + data.len()
+    }
+
+    fn write_field<W: Write>(writer: &mut W, field_type: u8, data: &[u8]) -> Result<()> {
+        if field_type == FIELD_EOS {
+            return writer.write_all(&[FIELD_EOS]);
+        }
+
+        // A type byte followed by a varint of at most 10 bytes
+        let mut header = [field_type; 11];
+        let mut header_len = 1;
+        let mut length = data.len();
+
+        while length >= 0x80 {
+            header[header_len] = (length as u8) | 0x80;
+            header_len += 1;
+            length >>= 7;
+        }
+
+        header[header_len] = length as u8;
+        header_len += 1;
+
+        writer.write_all(&header[..header_len])?;
+        writer.write_all(data)
+    }
+
+    // Call the given function with the type and data of every field of the
+    // serialized token, in order, including the end-of-section markers
+    fn visit_fields<F>(&self, mut visit: F) -> Result<()>
+        where F: FnMut(u8, &[u8]) -> Result<()>
+    {
+        if let Some(ref location) = self.location {
+            visit(FIELD_LOCATION, location)?;
+        }
+
+        visit(FIELD_IDENTIFIER, &self.identifier)?;
+        visit(FIELD_EOS, &[])?;
+
+        for caveat in &self.caveats {
+            if let Some(ref caveat_location) = caveat.caveat_location {
+                visit(FIELD_LOCATION, caveat_location)?;
+            }
+
+            visit(FIELD_IDENTIFIER, &caveat.caveat_id)?;
+
+            if let Some(ref verification_id) = caveat.verification_id {
+                visit(FIELD_VID, verification_id)?;
+            }
+
+            visit(FIELD_EOS, &[])?;
+        }
+
+        visit(FIELD_EOS, &[])?;
+        visit(FIELD_SIGNATURE, self.tag.as_bytes())
+    }
+
+    fn binary_len(&self) -> usize {
+        let mut length = 1;
+
+        // Counting never fails
+        let _ = self.visit_fields(|field_type, data| {
+            length += V2Token::field_len(field_type, data);
+            Ok(())
+        });
+
+        length
+    }
+
+    fn write_binary<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&[VERSION])?;
+        self.visit_fields(|field_type, data| V2Token::write_field(writer, field_type, data))
+    }
+}
+
+impl Token for V2Token {
+    fn new(key: &RootKey, identifier: Vec<u8>, location: Option<Vec<u8>>) -> V2Token {
+        verification::mint(key, identifier, location)
+    }
+
+    fn identifier(&self) -> &[u8] {
+        &self.identifier
+    }
+
+    fn deserialize(macaroon: Vec<u8>) -> Result<V2Token> {
+        let mut reader = Reader::new(&macaroon);
+
+        if reader.read_byte()? != VERSION {
+            return Err(Error::UnknownVersion);
+        }
+
+        // Parse the header section: optional location and identifier
+        let location = reader.read_optional(FIELD_LOCATION)?.map(|l| l.to_vec());
+        let identifier = reader.read_optional(FIELD_IDENTIFIER)?
+            .ok_or(Error::MissingIdentifier)?
+            .to_vec();
+        reader.read_eos()?;
+
+        // Parse caveat sections until the empty section terminating the list
+        let mut caveats: Vec<Caveat> = Vec::new();
+
+        while reader.peek() != Some(FIELD_EOS) {
+            let caveat_location = reader.read_optional(FIELD_LOCATION)?.map(|l| l.to_vec());
+            let caveat_id = reader.read_optional(FIELD_IDENTIFIER)?
+                .ok_or(Error::PacketOrdering)?
+                .to_vec();
+            let verification_id = reader.read_optional(FIELD_VID)?.map(|v| v.to_vec());
+            reader.read_eos()?;
+
+            caveats.push(Caveat {
+                caveat_id,
+                caveat_key: None,
+                verification_id,
+                caveat_location,
+            });
+        }
+
+        reader.read_eos()?;
+
+        // Parse the signature, which must be the last field
+        if reader.is_empty() {
+            return Err(Error::MissingSignature);
+        }
+
+        let signature = reader.read_field()?;
+
+        if signature.field_type != FIELD_SIGNATURE {
+            return Err(Error::PacketOrdering);
+        }
+
+        if signature.data.len() != TAGBYTES {
+            return Err(Error::SignatureLength);
+        }
+
+        if !reader.is_empty() {
+            return Err(Error::PacketOrdering);
+        }
+
+        let mut tag = [0u8; TAGBYTES];
+        tag.copy_from_slice(signature.data);
+
+        Ok(V2Token {
+            identifier,
+            location,
+            caveats,
+            tag: Signature::new(tag),
+        })
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut result: Vec<u8> = Vec::new();
+        self.serialize_into_vec(&mut result, Encoding::Raw)?;
+        Ok(result)
+    }
+
+    fn serialized_len(&self, encoding: Encoding) -> Result<usize> {
+        let length = self.binary_len();
+
+        match encoding {
+            Encoding::Base64 => Ok(base64::encoded_len(length)),
+            Encoding::Raw => Ok(length),
+        }
+    }
+
+    fn serialize_into<W: Write>(&self, writer: &mut W, encoding: Encoding) -> Result<()> {
+        match encoding {
+            Encoding::Base64 => {
+                let mut base64_writer = Base64Writer::new(writer);
+                self.write_binary(&mut base64_writer)?;
+                base64_writer.finish()
+            }
+            Encoding::Raw => self.write_binary(writer),
+        }
+    }
+
+    fn add_caveat_with_rng<R: Rng>(&self, caveat: &Caveat, rng: &mut R) -> Result<V2Token> {
+        verification::add_caveat(self, caveat, rng)
+    }
+
+    fn prepare_for_request(&self, discharge: &V2Token) -> V2Token {
+        verification::prepare_for_request(self, discharge)
+    }
+
+    fn verify_detailed<V: CaveatVerifier>(&self, key: &RootKey, verifier: V) -> VerificationResult {
+        verification::verify(self, key, &verifier)
+    }
+
+    fn verify_with_discharges_detailed<V: CaveatVerifier>(&self,
+                                                          key: &RootKey,
+                                                          verifier: V,
+                                                          discharges: &[V2Token])
+                                                          -> VerificationResult {
+        verification::verify_with_discharges(self, key, &verifier, discharges)
+    }
+
+    fn verify_discharge<V: CaveatVerifier>(&self,
+                                           authorizing: &V2Token,
+                                           caveat_key: &RootKey,
+                                           verifier: V)
+                                           -> Result<()> {
+        verification::verify_discharge(self, authorizing, caveat_key, &verifier)
+            .map_err(Error::from)
+    }
+
+    fn authenticate_without_verifying(&self, key: &RootKey) -> Result<()> {
+        verification::authenticate(self, key)
+    }
+}
+
+impl From<V1Token> for V2Token {
+    fn from(token: V1Token) -> V2Token {
+        V2Token {
+            identifier: token.identifier,
+            location: token.location,
+            caveats: token.caveats,
+            tag: token.tag,
+        }
+    }
+}
+
+impl From<V2Token> for V1Token {
+    fn from(token: V2Token) -> V1Token {
+        V1Token {
+            identifier: token.identifier,
+            location: token.location,
+            caveats: token.caveats,
+            tag: token.tag,
+        }
+    }
+}
+
+impl Signed for V2Token {
+    fn from_parts(identifier: Vec<u8>,
+                  location: Option<Vec<u8>>,
+                  caveats: Vec<Caveat>,
+                  tag: Signature)
+                  -> V2Token {
+        V2Token {
+            identifier,
+            location,
+            caveats,
+            tag,
+        }
+    }
+
+    fn identifier(&self) -> &[u8] {
+        &self.identifier
+    }
+
+    fn location(&self) -> Option<&[u8]> {
+        self.location.as_ref().map(|location| &location[..])
+    }
+
+    fn caveats(&self) -> &[Caveat] {
+        &self.caveats
+    }
+
+    fn tag(&self) -> &[u8; TAGBYTES] {
+        self.tag.as_bytes()
+    }
+}
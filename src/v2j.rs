@@ -0,0 +1,152 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str;
+
+use serde_json::{self, Value};
+
+use base64;
+use caveat::Caveat;
+use crypto::TAGBYTES;
+use error::{Error, Result};
+use key::Signature;
+use v2::V2Token;
+
+const VERSION: u64 = 2;
+
+// A V2J token. Every field may be given either as UTF-8 (e.g. "i") or as
+// base64 (e.g. "i64"). Duplicate fields are rejected, since different parsers
+// would disagree on which one to use.
+#[derive(Serialize, Deserialize)]
+struct JsonToken {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    c: Option<Vec<JsonCaveat>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    i: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    i64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    l: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    l64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    s: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    s64: Option<String>,
+    // Any JSON value is accepted here, so that tokens of other versions are
+    // reported as such rather than as malformed JSON
+    #[serde(skip_serializing_if = "Option::is_none")]
+    v: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonCaveat {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    i: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    i64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    l: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    l64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    v: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    v64: Option<String>,
+}
+
+// Encode a field as UTF-8 if possible, or as base64 otherwise, returning the
+// plain and base64 variants
+fn encode_field(value: &[u8]) -> (Option<String>, Option<String>) {
+    match str::from_utf8(value) {
+        Ok(string) => (Some(string.into()), None),
+        Err(_) => (None, Some(base64::encode(value))),
+    }
+}
+
+// Decode a field given by either its plain or its base64 variant
+fn decode_field(plain: Option<String>, encoded: Option<String>) -> Result<Option<Vec<u8>>> {
+    match (plain, encoded) {
+        (None, None) => Ok(None),
+        (Some(value), None) => Ok(Some(value.into_bytes())),
+        (None, Some(value)) => base64::decode(value.as_bytes()).map(Some),
+        (Some(_), Some(_)) => Err(Error::Json),
+    }
+}
+
+impl V2Token {
+    pub fn serialize_json(&self) -> Result<Vec<u8>> {
+        let (i, i64) = encode_field(&self.identifier);
+        let (l, l64) = match self.location {
+            Some(ref location) => encode_field(location),
+            None => (None, None),
+        };
+
+        let caveats = self.caveats
+            .iter()
+            .map(|caveat| {
+                let (i, i64) = encode_field(&caveat.caveat_id);
+                let (v, v64) = match caveat.verification_id {
+                    Some(ref verification_id) => encode_field(verification_id),
+                    None => (None, None),
+                };
+                let (l, l64) = match caveat.caveat_location {
+                    Some(ref caveat_location) => encode_field(caveat_location),
+                    None => (None, None),
+                };
+
+                JsonCaveat { i, i64, l, l64, v, v64 }
+            })
+            .collect();
+
+        let token = JsonToken {
+            c: Some(caveats),
+            i,
+            i64,
+            l,
+            l64,
+            s: None,
+            s64: Some(base64::encode(self.tag.as_bytes())),
+            v: Some(Value::from(VERSION)),
+        };
+
+        serde_json::to_vec(&token).map_err(|_e| Error::Json)
+    }
+
+    pub fn deserialize_json(macaroon: Vec<u8>) -> Result<V2Token> {
+        let token: JsonToken = serde_json::from_slice(&macaroon).map_err(|_e| Error::Json)?;
+
+        if token.v.as_ref().and_then(Value::as_u64) != Some(VERSION) {
+            return Err(Error::UnknownVersion);
+        }
+
+        let location = decode_field(token.l, token.l64)?;
+        let identifier = decode_field(token.i, token.i64)?.ok_or(Error::MissingIdentifier)?;
+
+        let mut caveats: Vec<Caveat> = Vec::new();
+
+        for caveat in token.c.unwrap_or_default() {
+            caveats.push(Caveat {
+                caveat_id: decode_field(caveat.i, caveat.i64)?.ok_or(Error::MalformedPacket)?,
+                caveat_key: None,
+                verification_id: decode_field(caveat.v, caveat.v64)?,
+                caveat_location: decode_field(caveat.l, caveat.l64)?,
+            });
+        }
+
+        let signature = decode_field(token.s, token.s64)?.ok_or(Error::MissingSignature)?;
+
+        if signature.len() != TAGBYTES {
+            return Err(Error::SignatureLength);
+        }
+
+        let mut tag = [0u8; TAGBYTES];
+        tag.copy_from_slice(&signature);
+
+        Ok(V2Token {
+            identifier,
+            location,
+            caveats,
+            tag: Signature::new(tag),
+        })
+    }
+}
@@ -0,0 +1,345 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+
+use caveat::Caveat;
+use crypto::{self, SecretKey, Tag};
+use error::{Error, FailureKind, Result, VerificationError, VerificationResult};
+use key::{RootKey, Signature};
+use rng::Rng;
+use verifier::{CaveatVerifier, Outcome};
+
+// The parts of a token, regardless of its format. Signing and verification
+// only need these, so each format just implements its codec on top.
+pub trait Signed {
+    fn from_parts(identifier: Vec<u8>,
+                  location: Option<Vec<u8>>,
+                  caveats: Vec<Caveat>,
+                  tag: Signature)
+                  -> Self;
+    fn identifier(&self) -> &[u8];
+    fn location(&self) -> Option<&[u8]>;
+    fn caveats(&self) -> &[Caveat];
+    fn tag(&self) -> &Tag;
+}
+
+// Mint a token with no caveats
+pub fn mint<T: Signed>(key: &RootKey, identifier: Vec<u8>, location: Option<Vec<u8>>) -> T {
+    let tag = crypto::hmac(&*SecretKey::derive(key.as_bytes()), &identifier);
+    T::from_parts(identifier, location, Vec::new(), Signature::new(tag))
+}
+
+pub fn add_caveat<T: Signed, R: Rng>(token: &T, caveat: &Caveat, rng: &mut R) -> Result<T> {
+    let (new_tag, new_caveat) = crypto::add_caveat(token.tag(), caveat, rng)?;
+
+    let mut new_caveats = token.caveats().to_vec();
+    new_caveats.push(new_caveat);
+
+    Ok(T::from_parts(token.identifier().to_vec(),
+                     token.location().map(|location| location.to_vec()),
+                     new_caveats,
+                     Signature::new(new_tag)))
+}
+
+// Bind a discharge to the token it authorizes
+pub fn prepare_for_request<T: Signed>(token: &T, discharge: &T) -> T {
+    T::from_parts(discharge.identifier().to_vec(),
+                  discharge.location().map(|location| location.to_vec()),
+                  discharge.caveats().to_vec(),
+                  Signature::new(crypto::bind(token.tag(), discharge.tag())))
+}
+
+// Recompute the (unbound) signature of a token from the given root key
+fn compute_tag<T: Signed>(token: &T, key: &RootKey) -> SecretKey {
+    let key = SecretKey::derive(key.as_bytes());
+    SecretKey::new(crypto::signature(&key, token.identifier(), token.caveats()))
+}
+
+// Check a token's signature without looking at its caveats
+pub fn authenticate<T: Signed>(token: &T, key: &RootKey) -> Result<()> {
+    if crypto::secure_eq(&*compute_tag(token, key), token.tag()) {
+        Ok(())
+    } else {
+        Err(Error::VerificationFailed)
+    }
+}
+
+fn signature_failure() -> VerificationError {
+    VerificationError {
+        kind: FailureKind::Signature,
+        caveat_index: None,
+        predicate: None,
+        outcome: None,
+        discharge_error: None,
+        error: Error::VerificationFailed,
+    }
+}
+
+// Fail unless the verifier was satisfied by the caveat
+fn check_outcome(outcome: Outcome,
+                 kind: FailureKind,
+                 index: usize,
+                 caveat: &Caveat,
+                 error: Error)
+                 -> VerificationResult {
+    match outcome {
+        Outcome::Satisfied => Ok(()),
+        outcome => {
+            Err(VerificationError {
+                outcome: Some(outcome),
+                ..caveat_failure(kind, index, caveat, error)
+            })
+        }
+    }
+}
+
+fn caveat_failure(kind: FailureKind,
+                  index: usize,
+                  caveat: &Caveat,
+                  error: Error)
+                  -> VerificationError {
+    VerificationError {
+        kind,
+        caveat_index: Some(index),
+        predicate: Some(caveat.caveat_id.clone()),
+        outcome: None,
+        discharge_error: None,
+        error,
+    }
+}
+
+// Check a token's signature, then its caveats. Third-party caveats are left
+// to the verifier rather than checked against discharges.
+pub fn verify<T, V>(token: &T, key: &RootKey, verifier: &V) -> VerificationResult
+    where T: Signed,
+          V: CaveatVerifier
+{
+    verify_tag(token, &compute_tag(token, key), verifier)
+}
+
+// Verify a discharge on its own, given the key of the caveat it discharges
+// and the token it is bound to
+pub fn verify_discharge<T, V>(discharge: &T,
+                              authorizing: &T,
+                              caveat_key: &RootKey,
+                              verifier: &V)
+                              -> VerificationResult
+    where T: Signed,
+          V: CaveatVerifier
+{
+    let tag = compute_tag(discharge, caveat_key);
+    let bound_tag = SecretKey::new(crypto::bind(authorizing.tag(), &tag));
+    verify_tag(discharge, &bound_tag, verifier)
+}
+
+fn verify_tag<T, V>(token: &T, expected_tag: &Tag, verifier: &V) -> VerificationResult
+    where T: Signed,
+          V: CaveatVerifier
+{
+    if !crypto::secure_eq(expected_tag, token.tag()) {
+        return Err(signature_failure());
+    }
+
+    verify_caveats(token, verifier)
+}
+
+fn verify_caveats<T: Signed, V: CaveatVerifier>(token: &T, verifier: &V) -> VerificationResult {
+    for (index, caveat) in token.caveats().iter().enumerate() {
+        if caveat.verification_id.is_none() {
+            check_outcome(verifier.check_first_party(&caveat.caveat_id),
+                          FailureKind::FirstParty,
+                          index,
+                          caveat,
+                          Error::FirstPartyCaveatFailed)?;
+        } else {
+            check_outcome(verifier.check_third_party(&caveat.caveat_id),
+                          FailureKind::ThirdParty,
+                          index,
+                          caveat,
+                          Error::VerificationFailed)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Verify a token along with the discharges for its third-party caveats.
+// Every discharge must be bound to the token and used, with one discharge
+// satisfying every third-party caveat with its caveat id, as gathered by
+// discharge::collect_discharges. The token and all of its discharges are
+// authenticated before the verifier sees any of their caveats, so it is never
+// asked about forged ones.
+pub fn verify_with_discharges<T, V>(token: &T,
+                                    key: &RootKey,
+                                    verifier: &V,
+                                    discharges: &[T])
+                                    -> VerificationResult
+    where T: Signed,
+          V: CaveatVerifier
+{
+    let mut context = Context {
+        authorizing_tag: token.tag(),
+        verifier,
+        discharges,
+        used: vec![false; discharges.len()],
+        active: vec![false; discharges.len()],
+        authenticated: discharges.iter().map(|_| None).collect(),
+    };
+
+    context.authenticate(token, &SecretKey::derive(key.as_bytes()), false)?;
+
+    if context.used.iter().any(|&used| !used) {
+        return Err(VerificationError {
+            kind: FailureKind::ThirdParty,
+            caveat_index: None,
+            predicate: None,
+            outcome: None,
+            discharge_error: None,
+            error: Error::UnusedDischarge,
+        });
+    }
+
+    // Checking marks the discharges used again, so that a discharge's caveats
+    // are checked once however many caveats it satisfies
+    context.used = vec![false; discharges.len()];
+    context.check(token)
+}
+
+struct Context<'a, T: Signed + 'a, V: CaveatVerifier + 'a> {
+    authorizing_tag: &'a Tag,
+    verifier: &'a V,
+    discharges: &'a [T],
+    used: Vec<bool>,
+    // Discharges being authenticated or checked further up, which can't be
+    // needed again unless the discharges form a cycle
+    active: Vec<bool>,
+    // The caveat key each discharge was authenticated with
+    authenticated: Vec<Option<SecretKey>>,
+}
+
+impl<'a, T: Signed, V: CaveatVerifier> Context<'a, T, V> {
+    // Recompute the tag of a token and of the discharges for its third-party
+    // caveats. A token's own signature failure takes precedence over those
+    // of its discharges, since nothing said by the caveats of a forged token
+    // is meaningful.
+    fn authenticate(&mut self, token: &T, key: &Tag, is_discharge: bool) -> VerificationResult {
+        let mut tag = SecretKey::new(crypto::hmac(key, token.identifier()));
+        let mut failure = None;
+
+        for (index, caveat) in token.caveats().iter().enumerate() {
+            if failure.is_none() {
+                failure = self.authenticate_caveat(index, caveat, &tag).err();
+            }
+
+            tag = SecretKey::new(crypto::chain_caveat(&tag, caveat));
+        }
+
+        if is_discharge {
+            tag = SecretKey::new(crypto::bind(self.authorizing_tag, &tag));
+        }
+
+        if !crypto::secure_eq(&*tag, token.tag()) {
+            return Err(signature_failure());
+        }
+
+        match failure {
+            Some(failure) => Err(failure),
+            None => Ok(()),
+        }
+    }
+
+    fn authenticate_caveat(&mut self,
+                           index: usize,
+                           caveat: &Caveat,
+                           tag: &Tag)
+                           -> VerificationResult {
+        let verification_id = match caveat.verification_id {
+            Some(ref verification_id) => verification_id,
+            None => return Ok(()),
+        };
+
+        let third_party_failure = |error| {
+            caveat_failure(FailureKind::ThirdParty, index, caveat, error)
+        };
+
+        let caveat_key = crypto::decrypt_caveat_key(tag, verification_id)
+            .map_err(|_e| third_party_failure(Error::VerificationFailed))?;
+        let discharge_index = self.find_discharge(&caveat.caveat_id)
+            .map_err(&third_party_failure)?;
+        self.used[discharge_index] = true;
+
+        // Another caveat with the same id and key already authenticated it
+        if let Some(ref key) = self.authenticated[discharge_index] {
+            if crypto::secure_eq(&**key, &*caveat_key) {
+                return Ok(());
+            }
+        }
+
+        let discharges = self.discharges;
+        self.active[discharge_index] = true;
+        let result = self.authenticate(&discharges[discharge_index], &caveat_key, true);
+        self.active[discharge_index] = false;
+
+        result.map_err(|discharge_error| discharge_failure(index, caveat, discharge_error))?;
+        self.authenticated[discharge_index] = Some(caveat_key);
+        Ok(())
+    }
+
+    // Check the caveats of an authenticated token and of its discharges
+    fn check(&mut self, token: &T) -> VerificationResult {
+        for (index, caveat) in token.caveats().iter().enumerate() {
+            if caveat.verification_id.is_none() {
+                check_outcome(self.verifier.check_first_party(&caveat.caveat_id),
+                              FailureKind::FirstParty,
+                              index,
+                              caveat,
+                              Error::FirstPartyCaveatFailed)?;
+                continue;
+            }
+
+            let discharge_index = self.find_discharge(&caveat.caveat_id)
+                .map_err(|error| caveat_failure(FailureKind::ThirdParty, index, caveat, error))?;
+
+            if mem::replace(&mut self.used[discharge_index], true) {
+                continue;
+            }
+
+            let discharges = self.discharges;
+            self.active[discharge_index] = true;
+            let result = self.check(&discharges[discharge_index]);
+            self.active[discharge_index] = false;
+
+            result.map_err(|discharge_error| discharge_failure(index, caveat, discharge_error))?;
+        }
+
+        Ok(())
+    }
+
+    // Find the first discharge for the given caveat id. A discharge needed
+    // again while its own caveats are being looked at means the discharges
+    // form a cycle.
+    fn find_discharge(&self, caveat_id: &[u8]) -> Result<usize> {
+        let index = self.discharges
+            .iter()
+            .position(|discharge| discharge.identifier() == caveat_id)
+            .ok_or(Error::MissingDischarge)?;
+
+        if self.active[index] {
+            return Err(Error::CyclicDischarge);
+        }
+
+        Ok(index)
+    }
+}
+
+// The failure of a third-party caveat whose discharge failed
+fn discharge_failure(index: usize,
+                     caveat: &Caveat,
+                     discharge_error: VerificationError)
+                     -> VerificationError {
+    VerificationError {
+        error: discharge_error.error,
+        discharge_error: Some(Box::new(discharge_error)),
+        ..caveat_failure(FailureKind::ThirdParty, index, caveat, Error::VerificationFailed)
+    }
+}
@@ -1,24 +1,132 @@
-use std::sync::Arc;
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::result;
+use core::str;
+#[cfg(feature = "std")]
 use std::rc::Rc;
+#[cfg(feature = "std")]
+use std::sync::Arc;
+
+use error::VerificationError;
+use key::RootKey;
+use token::Token;
 
 pub trait Verifier {
     fn verify_first_party(&self, _caveat: &[u8]) -> bool { false }
     fn verify_third_party(&self, _caveat: &[u8]) -> bool { false }
 }
 
+// What a CaveatVerifier concluded about a caveat
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Outcome {
+    Satisfied,
+    // The caveat is understood, but does not hold for this request
+    Violated(String),
+    // The verifier does not know what the caveat means
+    Unrecognized,
+}
+
+// A verifier that can explain its decisions. Verification only succeeds if
+// every caveat is Satisfied, so unrecognized caveats fail closed.
+pub trait CaveatVerifier {
+    fn check_first_party(&self, _caveat: &[u8]) -> Outcome { Outcome::Unrecognized }
+    fn check_third_party(&self, _caveat: &[u8]) -> Outcome { Outcome::Unrecognized }
+}
+
+// The outcome of a Verifier, which can't tell a violated caveat from one it
+// doesn't recognize
+fn outcome(satisfied: bool) -> Outcome {
+    if satisfied {
+        Outcome::Satisfied
+    } else {
+        Outcome::Unrecognized
+    }
+}
+
+// Checked
+
+// Adapts any Verifier into a CaveatVerifier
+pub struct Checked<V: Verifier>(pub V);
+
+impl<V: Verifier> CaveatVerifier for Checked<V> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        outcome(self.0.verify_first_party(caveat))
+    }
+
+    fn check_third_party(&self, caveat: &[u8]) -> Outcome {
+        outcome(self.0.verify_third_party(caveat))
+    }
+}
+
 // Pointer primitives
 
-impl<'a, V: Verifier> Verifier for &'a V {
+impl<V: CaveatVerifier + ?Sized> CaveatVerifier for &V {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        (**self).check_first_party(caveat)
+    }
+
+    fn check_third_party(&self, caveat: &[u8]) -> Outcome {
+        (**self).check_third_party(caveat)
+    }
+}
+
+impl<V: CaveatVerifier + ?Sized> CaveatVerifier for &mut V {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        (**self).check_first_party(caveat)
+    }
+
+    fn check_third_party(&self, caveat: &[u8]) -> Outcome {
+        (**self).check_third_party(caveat)
+    }
+}
+
+impl<V: CaveatVerifier + ?Sized> CaveatVerifier for Box<V> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        (**self).check_first_party(caveat)
+    }
+
+    fn check_third_party(&self, caveat: &[u8]) -> Outcome {
+        (**self).check_third_party(caveat)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<V: CaveatVerifier + ?Sized> CaveatVerifier for Rc<V> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        (**self).check_first_party(caveat)
+    }
+
+    fn check_third_party(&self, caveat: &[u8]) -> Outcome {
+        (**self).check_third_party(caveat)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<V: CaveatVerifier + ?Sized> CaveatVerifier for Arc<V> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        (**self).check_first_party(caveat)
+    }
+
+    fn check_third_party(&self, caveat: &[u8]) -> Outcome {
+        (**self).check_third_party(caveat)
+    }
+}
+
+impl<V: Verifier + ?Sized> Verifier for &V {
     fn verify_first_party(&self, caveat: &[u8]) -> bool {
         (**self).verify_first_party(caveat)
     }
 
     fn verify_third_party(&self, caveat: &[u8]) -> bool {
-        (**self).verify_first_party(caveat)
+        (**self).verify_third_party(caveat)
     }
 }
 
-impl<'a, V: Verifier> Verifier for &'a mut V {
+impl<V: Verifier + ?Sized> Verifier for &mut V {
     fn verify_first_party(&self, caveat: &[u8]) -> bool {
         (**self).verify_first_party(caveat)
     }
@@ -28,7 +136,7 @@ impl<'a, V: Verifier> Verifier for &'a mut V {
     }
 }
 
-impl<V: Verifier> Verifier for Box<V> {
+impl<V: Verifier + ?Sized> Verifier for Box<V> {
     fn verify_first_party(&self, caveat: &[u8]) -> bool {
         (**self).verify_first_party(caveat)
     }
@@ -38,7 +146,8 @@ impl<V: Verifier> Verifier for Box<V> {
     }
 }
 
-impl<V: Verifier> Verifier for Rc<V> {
+#[cfg(feature = "std")]
+impl<V: Verifier + ?Sized> Verifier for Rc<V> {
     fn verify_first_party(&self, caveat: &[u8]) -> bool {
         (**self).verify_first_party(caveat)
     }
@@ -48,7 +157,8 @@ impl<V: Verifier> Verifier for Rc<V> {
     }
 }
 
-impl<V: Verifier> Verifier for Arc<V> {
+#[cfg(feature = "std")]
+impl<V: Verifier + ?Sized> Verifier for Arc<V> {
     fn verify_first_party(&self, caveat: &[u8]) -> bool {
         (**self).verify_first_party(caveat)
     }
@@ -66,12 +176,20 @@ impl<F> Verifier for Func<F> where
     F: Fn(&str) -> bool
 {
     fn verify_first_party(&self, caveat: &[u8]) -> bool {
-        ::std::str::from_utf8(&caveat)
+        str::from_utf8(caveat)
         .map(&self.0)
         .unwrap_or(false)
     }
 }
 
+impl<F> CaveatVerifier for Func<F> where
+    F: Fn(&str) -> bool
+{
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        outcome(self.verify_first_party(caveat))
+    }
+}
+
 // ByteFunc
 
 pub struct ByteFunc<F: Fn(&[u8]) -> bool>(pub F);
@@ -84,31 +202,66 @@ impl<F> Verifier for ByteFunc<F> where
     }
 }
 
+impl<F> CaveatVerifier for ByteFunc<F> where
+    F: Fn(&[u8]) -> bool
+{
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        outcome(self.verify_first_party(caveat))
+    }
+}
+
 // LinkedVerifier
 
-pub struct LinkedVerifier<V1: Verifier, V2: Verifier> {
+// Satisfied by caveats that satisfy either verifier. A caveat neither
+// satisfies is Violated if either verifier says so, with its reason.
+pub struct LinkedVerifier<V1: CaveatVerifier, V2: CaveatVerifier> {
     verifier1: V1,
     verifier2: V2,
 }
 
-impl<V1: Verifier, V2: Verifier> LinkedVerifier<V1, V2> {
+impl<V1: CaveatVerifier, V2: CaveatVerifier> LinkedVerifier<V1, V2> {
     pub fn from(verifier1: V1, verifier2: V2) -> Self {
         LinkedVerifier {
-            verifier1: verifier1,
-            verifier2: verifier2,
+            verifier1,
+            verifier2,
+        }
+    }
+}
+
+// Satisfied if either outcome is, otherwise the first violation
+fn either(outcome1: Outcome, outcome2: Outcome) -> Outcome {
+    match (outcome1, outcome2) {
+        (Outcome::Satisfied, _) | (_, Outcome::Satisfied) => Outcome::Satisfied,
+        (Outcome::Violated(reason), _) | (_, Outcome::Violated(reason)) => {
+            Outcome::Violated(reason)
         }
+        _ => Outcome::Unrecognized,
     }
 }
 
-impl<V1: Verifier, V2: Verifier> Verifier for LinkedVerifier<V1, V2> {
+impl<V1: CaveatVerifier, V2: CaveatVerifier> Verifier for LinkedVerifier<V1, V2> {
     fn verify_first_party(&self, caveat: &[u8]) -> bool {
-           self.verifier1.verify_first_party(caveat)
-        || self.verifier2.verify_first_party(caveat)
+        self.check_first_party(caveat) == Outcome::Satisfied
     }
 
     fn verify_third_party(&self, caveat: &[u8]) -> bool {
-           self.verifier1.verify_third_party(caveat)
-        || self.verifier2.verify_third_party(caveat)
+        self.check_third_party(caveat) == Outcome::Satisfied
+    }
+}
+
+impl<V1: CaveatVerifier, V2: CaveatVerifier> CaveatVerifier for LinkedVerifier<V1, V2> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        match self.verifier1.check_first_party(caveat) {
+            Outcome::Satisfied => Outcome::Satisfied,
+            outcome1 => either(outcome1, self.verifier2.check_first_party(caveat)),
+        }
+    }
+
+    fn check_third_party(&self, caveat: &[u8]) -> Outcome {
+        match self.verifier1.check_third_party(caveat) {
+            Outcome::Satisfied => Outcome::Satisfied,
+            outcome1 => either(outcome1, self.verifier2.check_third_party(caveat)),
+        }
     }
 }
 
@@ -130,14 +283,318 @@ impl<Tag: AsRef<[u8]>, Value: AsRef<[u8]>> Verifier for Eq<Tag, Value> {
     }
 }
 
+// Caveats with the tag but another value are violated, and those without the
+// tag unrecognized
+impl<Tag: AsRef<[u8]>, Value: AsRef<[u8]>> CaveatVerifier for Eq<Tag, Value> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        let tag = self.0.as_ref();
+        let op = b" = ";
+
+        if self.verify_first_party(caveat) {
+            Outcome::Satisfied
+        } else if caveat.starts_with(tag) && caveat[tag.len()..].starts_with(op) {
+            Outcome::Violated(format!("{} is not {}",
+                                      String::from_utf8_lossy(tag),
+                                      String::from_utf8_lossy(self.1.as_ref())))
+        } else {
+            Outcome::Unrecognized
+        }
+    }
+}
+
+// AllOf
+
+// Satisfied by caveats that satisfy every one of the verifiers. If any of them
+// is violated so is the AllOf, with the first such reason. An empty AllOf
+// satisfies nothing.
+pub struct AllOf<'a>(pub Vec<Box<dyn CaveatVerifier + 'a>>);
+
+impl<'a> AllOf<'a> {
+    pub fn new() -> AllOf<'a> {
+        AllOf(Vec::new())
+    }
+
+    pub fn with<V: CaveatVerifier + 'a>(mut self, verifier: V) -> AllOf<'a> {
+        self.0.push(Box::new(verifier));
+        self
+    }
+
+    fn check<F>(&self, check: F) -> Outcome where
+        F: Fn(&dyn CaveatVerifier) -> Outcome
+    {
+        let mut result = if self.0.is_empty() {
+            Outcome::Unrecognized
+        } else {
+            Outcome::Satisfied
+        };
+        for verifier in &self.0 {
+            match check(verifier.as_ref()) {
+                Outcome::Satisfied => {}
+                Outcome::Violated(reason) => return Outcome::Violated(reason),
+                Outcome::Unrecognized => result = Outcome::Unrecognized,
+            }
+        }
+        result
+    }
+}
+
+impl<'a> Default for AllOf<'a> {
+    fn default() -> AllOf<'a> {
+        AllOf::new()
+    }
+}
+
+impl<'a> Verifier for AllOf<'a> {
+    fn verify_first_party(&self, caveat: &[u8]) -> bool {
+        self.check_first_party(caveat) == Outcome::Satisfied
+    }
+
+    fn verify_third_party(&self, caveat: &[u8]) -> bool {
+        self.check_third_party(caveat) == Outcome::Satisfied
+    }
+}
+
+impl<'a> CaveatVerifier for AllOf<'a> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        self.check(|verifier| verifier.check_first_party(caveat))
+    }
+
+    fn check_third_party(&self, caveat: &[u8]) -> Outcome {
+        self.check(|verifier| verifier.check_third_party(caveat))
+    }
+}
+
+// AnyOf
+
+// Satisfied by caveats that satisfy at least one of the verifiers. Otherwise
+// the first violation is reported, if any.
+pub struct AnyOf<'a>(pub Vec<Box<dyn CaveatVerifier + 'a>>);
+
+impl<'a> AnyOf<'a> {
+    pub fn new() -> AnyOf<'a> {
+        AnyOf(Vec::new())
+    }
+
+    pub fn with<V: CaveatVerifier + 'a>(mut self, verifier: V) -> AnyOf<'a> {
+        self.0.push(Box::new(verifier));
+        self
+    }
+
+    fn check<F>(&self, check: F) -> Outcome where
+        F: Fn(&dyn CaveatVerifier) -> Outcome
+    {
+        let mut result = Outcome::Unrecognized;
+        for verifier in &self.0 {
+            match check(verifier.as_ref()) {
+                Outcome::Satisfied => return Outcome::Satisfied,
+                Outcome::Violated(reason) => {
+                    if result == Outcome::Unrecognized {
+                        result = Outcome::Violated(reason);
+                    }
+                }
+                Outcome::Unrecognized => {}
+            }
+        }
+        result
+    }
+}
+
+impl<'a> Default for AnyOf<'a> {
+    fn default() -> AnyOf<'a> {
+        AnyOf::new()
+    }
+}
+
+impl<'a> Verifier for AnyOf<'a> {
+    fn verify_first_party(&self, caveat: &[u8]) -> bool {
+        self.check_first_party(caveat) == Outcome::Satisfied
+    }
+
+    fn verify_third_party(&self, caveat: &[u8]) -> bool {
+        self.check_third_party(caveat) == Outcome::Satisfied
+    }
+}
+
+impl<'a> CaveatVerifier for AnyOf<'a> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        self.check(|verifier| verifier.check_first_party(caveat))
+    }
+
+    fn check_third_party(&self, caveat: &[u8]) -> Outcome {
+        self.check(|verifier| verifier.check_third_party(caveat))
+    }
+}
+
+// Not
+
+// Satisfied by first-party caveats starting with the prefix that the
+// verifier finds violated, and violated by those it finds satisfied. Caveats
+// the verifier doesn't recognize stay unrecognized, as do caveats without the
+// prefix and third-party caveats.
+pub struct Not<P: AsRef<[u8]>, V: CaveatVerifier> {
+    prefix: P,
+    verifier: V,
+}
+
+impl<P: AsRef<[u8]>, V: CaveatVerifier> Not<P, V> {
+    pub fn with_prefix(prefix: P, verifier: V) -> Not<P, V> {
+        Not { prefix, verifier }
+    }
+}
+
+impl<P: AsRef<[u8]>, V: CaveatVerifier> Verifier for Not<P, V> {
+    fn verify_first_party(&self, caveat: &[u8]) -> bool {
+        self.check_first_party(caveat) == Outcome::Satisfied
+    }
+}
+
+impl<P: AsRef<[u8]>, V: CaveatVerifier> CaveatVerifier for Not<P, V> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        if !caveat.starts_with(self.prefix.as_ref()) {
+            return Outcome::Unrecognized;
+        }
+        match self.verifier.check_first_party(caveat) {
+            Outcome::Satisfied => Outcome::Violated("negated caveat holds".to_owned()),
+            Outcome::Violated(_) => Outcome::Satisfied,
+            Outcome::Unrecognized => Outcome::Unrecognized,
+        }
+    }
+}
+
+// Prefix
+
+// Passes first-party caveats starting with the prefix to the verifier, and
+// leaves all others unrecognized
+pub struct Prefix<P: AsRef<[u8]>, V: CaveatVerifier>(pub P, pub V);
+
+impl<P: AsRef<[u8]>, V: CaveatVerifier> Verifier for Prefix<P, V> {
+    fn verify_first_party(&self, caveat: &[u8]) -> bool {
+        self.check_first_party(caveat) == Outcome::Satisfied
+    }
+}
+
+impl<P: AsRef<[u8]>, V: CaveatVerifier> CaveatVerifier for Prefix<P, V> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        if caveat.starts_with(self.0.as_ref()) {
+            self.1.check_first_party(caveat)
+        } else {
+            Outcome::Unrecognized
+        }
+    }
+}
+
 // LinkVerifier
 
-pub trait LinkVerifier: Verifier + Sized {
-    fn link<V: Verifier>(self, verifier: V) -> LinkedVerifier<V, Self>;
+pub trait LinkVerifier: CaveatVerifier + Sized {
+    fn link<V: CaveatVerifier>(self, verifier: V) -> LinkedVerifier<V, Self>;
 }
 
-impl<T: Verifier> LinkVerifier for T {
-    fn link<V: Verifier>(self, verifier: V) -> LinkedVerifier<V, Self> {
+impl<T: CaveatVerifier> LinkVerifier for T {
+    fn link<V: CaveatVerifier>(self, verifier: V) -> LinkedVerifier<V, Self> {
         LinkedVerifier::from(verifier, self)
     }
 }
+
+// KeyedVerifier
+
+// Hands each first-party caveat to the verifier registered for its key, i.e.
+// the text before the first space ("account" for "account = 3735928559").
+// Caveats with no verifier for their key are unrecognized, so each verifier
+// only has to understand its own caveats.
+pub struct KeyedVerifier<'a> {
+    verifiers: BTreeMap<String, Box<dyn CaveatVerifier + 'a>>,
+}
+
+// Which verifier satisfied a caveat
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Claim {
+    pub caveat: Vec<u8>,
+    pub verifier: String,
+}
+
+// The caveats satisfied during a successful verification, in the order they
+// were checked, including those of discharges
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct CoverageReport {
+    pub claims: Vec<Claim>,
+}
+
+impl<'a> KeyedVerifier<'a> {
+    pub fn new() -> KeyedVerifier<'a> {
+        KeyedVerifier { verifiers: BTreeMap::new() }
+    }
+
+    // Register the verifier for caveats with the given key, replacing any
+    // verifier previously registered for it
+    pub fn add<V: CaveatVerifier + 'a>(mut self, key: &str, verifier: V) -> KeyedVerifier<'a> {
+        self.verifiers.insert(key.to_owned(), Box::new(verifier));
+        self
+    }
+
+    // Verify a token and its discharges, reporting which verifier satisfied
+    // each caveat
+    pub fn verify<T: Token>(&self,
+                            token: &T,
+                            key: &RootKey,
+                            discharges: &[T])
+                            -> result::Result<CoverageReport, VerificationError> {
+        let recorder = Recorder {
+            verifier: self,
+            claims: RefCell::new(Vec::new()),
+        };
+
+        token.verify_with_discharges_detailed(key, &recorder, discharges)?;
+
+        Ok(CoverageReport { claims: recorder.claims.into_inner() })
+    }
+
+    fn claim(&self, caveat: &[u8]) -> Option<(&str, &(dyn CaveatVerifier + 'a))> {
+        let key = caveat.split(|&byte| byte == b' ').next().unwrap_or(caveat);
+        let key = str::from_utf8(key).ok()?;
+
+        self.verifiers
+            .get_key_value(key)
+            .map(|(key, verifier)| (&key[..], &**verifier))
+    }
+}
+
+impl<'a> Default for KeyedVerifier<'a> {
+    fn default() -> KeyedVerifier<'a> {
+        KeyedVerifier::new()
+    }
+}
+
+impl<'a> CaveatVerifier for KeyedVerifier<'a> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        match self.claim(caveat) {
+            Some((_, verifier)) => verifier.check_first_party(caveat),
+            None => Outcome::Unrecognized,
+        }
+    }
+}
+
+// Records the claims made while verifying a single token
+struct Recorder<'k, 'a: 'k> {
+    verifier: &'k KeyedVerifier<'a>,
+    claims: RefCell<Vec<Claim>>,
+}
+
+impl<'r, 'k, 'a> CaveatVerifier for &'r Recorder<'k, 'a> {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        let (key, verifier) = match self.verifier.claim(caveat) {
+            Some(claim) => claim,
+            None => return Outcome::Unrecognized,
+        };
+
+        let outcome = verifier.check_first_party(caveat);
+
+        if outcome == Outcome::Satisfied {
+            self.claims.borrow_mut().push(Claim {
+                caveat: caveat.to_vec(),
+                verifier: key.to_owned(),
+            });
+        }
+
+        outcome
+    }
+}
@@ -1,9 +1,38 @@
+// The tests cover std-only APIs such as JSON and expiry caveats
+#![cfg(feature = "std")]
+
+extern crate base64;
+extern crate hex;
 extern crate macaroons;
 
-use macaroons::caveat::Caveat;
-use macaroons::token::Token;
-use macaroons::v1::V1Token;
-use macaroons::verifier::{Func, LinkVerifier};
+use std::cell::Cell;
+use std::env;
+use std::fs;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+
+use macaroons::async_verifier::{AsyncVerifier, CheckFuture};
+use macaroons::caveat::{Caveat, Predicate};
+use macaroons::discharge;
+use macaroons::error::{Error, FailureKind};
+use macaroons::expiry::{self, Expiry, FixedClock};
+use macaroons::format::{Format, Macaroon};
+use macaroons::key::{RootKey, Signature};
+use macaroons::key_store::{self, FileKeyStore, MemoryKeyStore, RootKeyStore};
+use macaroons::token::{Encoding, Token};
+use macaroons::v1::{TokenRef, V1Token};
+use macaroons::v2::V2Token;
+use macaroons::verifier::{self, AllOf, AnyOf, CaveatVerifier, Checked, Claim, Func,
+                          KeyedVerifier, LinkVerifier, Not, Outcome, Prefix, Verifier};
 
 const EMPTY_TAG: [u8; 32] = [0xe3, 0xd9, 0xe0, 0x29, 0x08, 0x52, 0x6c, 0x4c, 0x00, 0x39, 0xae,
                              0x15, 0x11, 0x41, 0x15, 0xd9, 0x7f, 0xdd, 0x68, 0xbf, 0x2b, 0xa3,
@@ -15,12 +44,42 @@ const EXPECTED_TAG_WITH_FIRST_PARTY_CAVEATS: [u8; 32] = [0x19, 0x7b, 0xac, 0x7a,
                                                          0x0e, 0x44, 0xd8, 0x8c, 0xe1, 0xa9, 0x98,
                                                          0xc2, 0x3d, 0xbd, 0x67];
 
-fn example_key() -> Vec<u8> {
-    Vec::from("this is our super secret key; only we should know it")
+// EXPECTED_TAG_WITH_FIRST_PARTY_CAVEATS bound to EMPTY_TAG
+const EXPECTED_BOUND_TAG: [u8; 32] = [0x75, 0xd9, 0xa3, 0x66, 0xcf, 0xa1, 0x08, 0x8a, 0x40, 0x2d, 0x3a,
+                                      0xa5, 0xac, 0x36, 0x95, 0x7e, 0x5c, 0xb7, 0x63, 0x50, 0xac, 0x7f,
+                                      0x58, 0xf4, 0x23, 0xb6, 0x8d, 0x92, 0x36, 0x17, 0x50, 0x5f];
+
+// The libmacaroons README's third-party caveat example, minted by this crate
+// with the nonce used to encrypt the caveat key fixed to 00 01 02 ... 17
+const FIXED_NONCE_TAG_WITH_THIRD_PARTY_CAVEAT: [u8; 32] = [0x12, 0xe8, 0x20, 0x36, 0x7c, 0x8a, 0x8b,
+                                                           0x68, 0xd6, 0xc8, 0xd9, 0xda, 0x3c, 0x4a,
+                                                           0xa4, 0xe8, 0xbd, 0x31, 0xed, 0x60, 0x6b,
+                                                           0xc0, 0x1a, 0x2e, 0x0e, 0xb3, 0x81, 0xa9,
+                                                           0x06, 0x80, 0xa4, 0xad];
+
+// Signatures published in the libmacaroons README's third-party caveat example
+const LIBMACAROONS_TAG_WITH_THIRD_PARTY_CAVEAT: [u8; 32] = [0xd2, 0x7d, 0xb2, 0xfd, 0x1f, 0x22, 0x76,
+                                                            0x0e, 0x4c, 0x3d, 0xae, 0x81, 0x37, 0xe2,
+                                                            0xd8, 0xfc, 0x1d, 0xf6, 0xc0, 0x74, 0x1c,
+                                                            0x18, 0xae, 0xd4, 0xb9, 0x72, 0x56, 0xbf,
+                                                            0x78, 0xd1, 0xf5, 0x5c];
+
+const LIBMACAROONS_UNBOUND_DISCHARGE_TAG: [u8; 32] = [0x82, 0xa8, 0x06, 0x81, 0xf9, 0xf3, 0x2d, 0x41,
+                                                      0x9a, 0xf1, 0x2f, 0x6a, 0x71, 0x78, 0x7a, 0x1b,
+                                                      0xac, 0x3a, 0xb1, 0x99, 0xdf, 0x93, 0x4e, 0xd9,
+                                                      0x50, 0xdd, 0xf2, 0x0c, 0x25, 0xac, 0x8c, 0x65];
+
+const LIBMACAROONS_BOUND_DISCHARGE_TAG: [u8; 32] = [0x2e, 0xb0, 0x1d, 0x0d, 0xd2, 0xb4, 0x47, 0x53,
+                                                    0x30, 0x73, 0x91, 0x40, 0x18, 0x86, 0x48, 0xcf,
+                                                    0x25, 0xdd, 0xa0, 0x42, 0x5e, 0xa9, 0xf6, 0x61,
+                                                    0xf1, 0x57, 0x4c, 0xa0, 0xa9, 0xea, 0xc5, 0x4e];
+
+fn example_key() -> RootKey {
+    RootKey::from("this is our super secret key; only we should know it")
 }
 
-fn invalid_key() -> Vec<u8> {
-    Vec::from("this is not the key you are looking for; it is evil")
+fn invalid_key() -> RootKey {
+    RootKey::from("this is not the key you are looking for; it is evil")
 }
 
 fn example_id() -> Vec<u8> {
@@ -97,6 +156,66 @@ fn example_serialized_with_first_party_caveats() -> Vec<u8> {
                TYjOGpmMI9vWcK")
 }
 
+fn example_v2_serialized_with_first_party_caveats() -> Vec<u8> {
+    let mut serialized = vec![0x02, 0x01, 0x0e];
+    serialized.extend(example_uri());
+    serialized.extend(&[0x02, 0x16]);
+    serialized.extend(example_id());
+    serialized.extend(&[0x00, 0x02, 0x0d]);
+    serialized.extend(b"test = caveat");
+    serialized.extend(&[0x00, 0x00, 0x06, 0x20]);
+    serialized.extend(&EXPECTED_TAG_WITH_FIRST_PARTY_CAVEATS);
+    serialized
+}
+
+fn example_shared_key() -> RootKey {
+    RootKey::from("a key shared between the first party and auth.mybank")
+}
+
+fn example_encrypted_third_party_caveat() -> Caveat {
+    discharge::third_party_caveat(&example_shared_key(),
+                                  example_caveat_key(),
+                                  Vec::from("user = alice"),
+                                  example_third_party_caveat_location())
+        .unwrap()
+}
+
+fn libmacaroons_key() -> RootKey {
+    RootKey::from("this is a different super-secret key; never use the same secret twice")
+}
+
+fn fixed_nonce_serialized_with_third_party_caveat() -> Vec<u8> {
+    Vec::from("MDAxY2xvY2F0aW9uIGh0dHA6Ly9teWJhbmsvCjAwMmNpZGVudGlmaWVyIHdlIHVzZWQgb3VyIG90aGV\
+               yIHNlY3JldCBrZXkKMDAxZGNpZCBhY2NvdW50ID0gMzczNTkyODU1OQowMDMwY2lkIHRoaXMgd2FzIGhv\
+               dyB3ZSByZW1pbmQgYXV0aCBvZiBrZXkvcHJlZAowMDUxdmlkIAABAgMEBQYHCAkKCwwNDg8QERITFBUWF\
+               -zLtsCHWWQzbQC369txo90p5BtqS4Qs4XHZZi68Zmfg_p4SOd3wGwgu17RNI3UmggowMDFiY2wgaHR0cD\
+               ovL2F1dGgubXliYW5rLwowMDJmc2lnbmF0dXJlIBLoIDZ8ioto1sjZ2jxKpOi9Me1ga8AaLg6zgakGgKS\
+               tCg")
+}
+
+fn fixed_nonce_serialized_bound_discharge() -> Vec<u8> {
+    Vec::from("MDAyMWxvY2F0aW9uIGh0dHA6Ly9hdXRoLm15YmFuay8KMDAzN2lkZW50aWZpZXIgdGhpcyB3YXMgaG9\
+               3IHdlIHJlbWluZCBhdXRoIG9mIGtleS9wcmVkCjAwMjBjaWQgdGltZSA8IDIwMTUtMDEtMDFUMDA6MDAK\
+               MDAyZnNpZ25hdHVyZSDwF3-XJTVRJvVrwAm4TsK1PIq8Gy_KzhU4zQPSgxG3yAo")
+}
+
+// The README's discharges, re-encoded from their inspect() output
+fn libmacaroons_serialized_discharge() -> Vec<u8> {
+    Vec::from("MDAyMWxvY2F0aW9uIGh0dHA6Ly9hdXRoLm15YmFuay8KMDAzN2lkZW50aWZpZXIgdGhpcyB3YXMgaG9\
+               3IHdlIHJlbWluZCBhdXRoIG9mIGtleS9wcmVkCjAwMjBjaWQgdGltZSA8IDIwMTUtMDEtMDFUMDA6MDAK\
+               MDAyZnNpZ25hdHVyZSCCqAaB-fMtQZrxL2pxeHobrDqxmd-TTtlQ3fIMJayMZQo")
+}
+
+fn libmacaroons_serialized_bound_discharge() -> Vec<u8> {
+    Vec::from("MDAyMWxvY2F0aW9uIGh0dHA6Ly9hdXRoLm15YmFuay8KMDAzN2lkZW50aWZpZXIgdGhpcyB3YXMgaG9\
+               3IHdlIHJlbWluZCBhdXRoIG9mIGtleS9wcmVkCjAwMjBjaWQgdGltZSA8IDIwMTUtMDEtMDFUMDA6MDAK\
+               MDAyZnNpZ25hdHVyZSAusB0N0rRHUzBzkUAYhkjPJd2gQl6p9mHxV0ygqerFTgo")
+}
+
+fn verify_libmacaroons_caveat(predicate: &str) -> bool {
+    predicate == "account = 3735928559" || predicate == "time < 2015-01-01T00:00"
+}
+
 #[test]
 fn empty_macaroon_signature() {
     let token = V1Token::new(&example_key(), example_id(), Some(example_uri()));
@@ -126,6 +245,54 @@ fn signature_with_third_party_caveat() {
                Some(example_third_party_caveat_location()));
 }
 
+#[test]
+fn tokens_do_not_keep_caveat_keys() {
+    let caveat = example_encrypted_third_party_caveat();
+    let token = example_token().add_caveat(&caveat);
+
+    assert_eq!(Some(example_caveat_key()), caveat.caveat_key);
+    assert_eq!(None, token.caveats[0].caveat_key);
+
+    // The discharge is still verified with the key recovered from the token
+    let bound_discharge = token.prepare_for_request(&example_discharge(&caveat));
+    assert!(token.verify_with_discharges(&example_key(), Func(verify_caveat), &[bound_discharge])
+        .is_ok());
+}
+
+#[test]
+fn root_keys() {
+    let key = RootKey::generate();
+    assert_eq!(32, key.as_bytes().len());
+    assert!(key != RootKey::generate());
+
+    let derived = RootKey::derive(&example_key(), b"key-1");
+    assert_eq!(derived, RootKey::derive(&example_key(), b"key-1"));
+    assert!(derived != RootKey::derive(&example_key(), b"key-2"));
+    assert!(derived != RootKey::derive(&invalid_key(), b"key-1"));
+
+    // PBKDF2-HMAC-SHA256 test vectors
+    let expected = "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b";
+    assert_eq!(hex::decode(expected).unwrap(),
+               RootKey::from_passphrase(b"password", b"salt", 1).unwrap().as_bytes());
+    let expected = "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a";
+    assert_eq!(hex::decode(expected).unwrap(),
+               RootKey::from_passphrase(b"password", b"salt", 4096).unwrap().as_bytes());
+    assert_eq!(Err(Error::Iterations),
+               RootKey::from_passphrase(b"password", b"salt", 0).map(|_| ()));
+
+    assert_eq!("RootKey(<redacted>)", format!("{:?}", example_key()));
+}
+
+#[test]
+fn signatures() {
+    let token = example_token().add_caveat(&example_first_party_caveat());
+
+    assert_eq!(Signature::new(EXPECTED_TAG_WITH_FIRST_PARTY_CAVEATS), token.tag);
+    assert!(Signature::new(EMPTY_TAG) != token.tag);
+    assert_eq!(&EXPECTED_TAG_WITH_FIRST_PARTY_CAVEATS, token.tag.as_bytes());
+    assert_eq!("Signature(<redacted>)", format!("{:?}", token.tag));
+}
+
 #[test]
 fn binary_serialization() {
     let token = example_token().add_caveat(&example_first_party_caveat());
@@ -137,12 +304,56 @@ fn binary_serialization() {
 fn binary_deserialization() {
     let token = V1Token::deserialize(example_serialized_with_first_party_caveats()).unwrap();
 
-    assert_eq!(example_uri(), token.location.unwrap());
+    assert_eq!(example_uri(), token.location.clone().unwrap());
     assert_eq!(example_id(), token.identifier);
 
     assert_eq!(EXPECTED_TAG_WITH_FIRST_PARTY_CAVEATS, token.tag)
 }
 
+#[test]
+fn borrowed_deserialization() {
+    let token = example_token()
+        .add_caveat(&example_first_party_caveat())
+        .add_caveat(&example_third_party_caveat())
+        .add_caveat(&example_first_party_caveat_different_prefix());
+    let token_data = URL_SAFE_NO_PAD.decode(token.serialize().unwrap()).unwrap();
+    let token_ref = TokenRef::parse(&token_data).unwrap();
+
+    assert_eq!(&example_uri()[..], token_ref.location.unwrap());
+    assert_eq!(&example_id()[..], token_ref.identifier);
+    assert_eq!(token.tag, token_ref.tag);
+
+    let caveats = token_ref.caveats();
+    assert_eq!(3, caveats.len());
+
+    for (caveat, caveat_ref) in token.caveats.iter().zip(caveats) {
+        assert_eq!(&caveat.caveat_id[..], caveat_ref.caveat_id);
+        assert_eq!(caveat.verification_id.as_ref().map(|vid| &vid[..]),
+                   caveat_ref.verification_id);
+        assert_eq!(caveat.caveat_location.as_ref().map(|location| &location[..]),
+                   caveat_ref.caveat_location);
+        assert_eq!(caveat.is_third_party(), caveat_ref.is_third_party());
+    }
+
+    let owned = token_ref.into_owned();
+    assert_eq!(token.serialize().unwrap(), owned.serialize().unwrap());
+    assert!(owned.authenticate_without_verifying(&example_key()).is_ok());
+}
+
+#[test]
+fn borrowed_deserialization_rejects_malformed_tokens() {
+    let token_data = URL_SAFE_NO_PAD.decode(example_serialized_with_first_party_caveats()).unwrap();
+
+    for length in 0..token_data.len() {
+        assert!(TokenRef::parse(&token_data[..length]).is_err());
+    }
+
+    assert_eq!(Err(Error::PacketOrdering),
+               TokenRef::parse(b"0011identifier x\n000avid y\n").map(|_| ()));
+    assert_eq!(Err(Error::MissingSignature),
+               TokenRef::parse(b"0011identifier x\n000acid y\n").map(|_| ()));
+}
+
 #[test]
 fn simple_verification() {
     let token = example_token().add_caveat(&example_first_party_caveat());
@@ -168,3 +379,1565 @@ fn verifying_predicates() {
     let multiple_verifier = Func(verify_caveat).link(Func(verify_other));
     assert!(token.verify(&example_key(), &multiple_verifier).is_ok());
 }
+
+#[test]
+fn predicate_caveats_are_canonical() {
+    let caveats = [(Caveat::eq("account", "3735928559"), "account = 3735928559"),
+                   (Caveat::not_eq("user", "alice"), "user != alice"),
+                   (Caveat::lt("count", "10"), "count < 10"),
+                   (Caveat::gt("count", "-1"), "count > -1"),
+                   (Caveat::is_in("action", &["read", "write"]), "action in read,write"),
+                   (Caveat::prefix("path", "/home/alice "), "path prefix /home/alice ")];
+
+    for &(ref caveat, expected) in &caveats {
+        let caveat = caveat.as_ref().unwrap();
+        assert_eq!(expected.as_bytes(), &caveat.caveat_id[..]);
+
+        // Parsing and re-serializing is the identity
+        let predicate = caveat.parse_predicate().unwrap();
+        assert_eq!(expected, predicate.to_string());
+    }
+
+    assert_eq!(Predicate::In("action".to_owned(), vec!["read".to_owned(), "".to_owned()]),
+               Predicate::parse(b"action in read,").unwrap());
+
+    // Caveats built this way can be checked by the simple verifiers
+    let token = example_token().add_caveat(&Caveat::eq("account", "3735928559").unwrap());
+    assert!(token.verify(&example_key(), verifier::Eq("account", "3735928559")).is_ok());
+}
+
+#[test]
+fn caveats_display_non_utf8_ids() {
+    // Caveats read from a token can hold any bytes
+    let token = example_token()
+        .add_caveat(&example_first_party_caveat())
+        .add_caveat(&Caveat::first_party(Vec::from(&b"account = \xff"[..])));
+    let token = V1Token::deserialize(token.serialize().unwrap()).unwrap();
+
+    assert_eq!("test = caveat", token.caveats[0].to_string());
+    assert_eq!("account = \u{fffd}", token.caveats[1].to_string());
+}
+
+#[test]
+fn predicate_caveats_reject_malformed_predicates() {
+    assert_eq!(Err(Error::MalformedPredicate),
+               Caveat::eq("two words", "value").map(|_| ()));
+    assert_eq!(Err(Error::MalformedPredicate), Caveat::eq("", "value").map(|_| ()));
+    assert_eq!(Err(Error::MalformedPredicate),
+               Caveat::is_in::<&str>("action", &[]).map(|_| ()));
+    assert_eq!(Err(Error::MalformedPredicate),
+               Caveat::is_in("action", &["read,write"]).map(|_| ()));
+
+    let malformed: [&[u8]; 8] = [b"",
+                                 b"account",
+                                 b"account =",
+                                 b"account  = 1",
+                                 b" = 1",
+                                 b"account == 1",
+                                 b"account\t= 1",
+                                 b"account = \xff"];
+
+    for predicate in &malformed {
+        assert_eq!(Err(Error::MalformedPredicate),
+                   Predicate::parse(predicate),
+                   "{:?}",
+                   predicate);
+    }
+}
+
+#[test]
+fn predicates_match_values() {
+    let predicate = |predicate: &str| Predicate::parse(predicate.as_bytes()).unwrap();
+
+    assert_eq!("account", predicate("account = 1").key());
+    assert!(predicate("account = 1").matches("1"));
+    assert!(!predicate("account = 1").matches("01"));
+    assert!(predicate("user != alice").matches("bob"));
+    assert!(!predicate("user != alice").matches("alice"));
+    assert!(predicate("action in read,write").matches("write"));
+    assert!(!predicate("action in read,write").matches("read,write"));
+    assert!(predicate("path prefix /home/").matches("/home/alice"));
+    assert!(!predicate("path prefix /home/").matches("/etc"));
+
+    // Ordering compares integers, and fails closed on anything else
+    assert!(predicate("count < 10").matches("9"));
+    assert!(!predicate("count < 10").matches("10"));
+    assert!(predicate("count > -5").matches("-4"));
+    assert!(!predicate("count > 5").matches("10a"));
+    assert!(!predicate("count < ten").matches("9"));
+}
+
+fn at(seconds: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+#[test]
+fn expiry_caveats() {
+    // 2030-01-01T00:00:00Z
+    let expiry_time = at(1_893_456_000);
+    let caveat = Caveat::expiry(expiry_time + Duration::from_millis(999)).unwrap();
+    assert_eq!(b"time < 2030-01-01T00:00:00Z", &caveat.caveat_id[..]);
+
+    let token = example_token()
+        .add_caveat(&caveat)
+        .add_caveat(&example_first_party_caveat());
+
+    let verifier_at = |time| {
+        Expiry::with_clock(FixedClock(time)).link(verifier::Eq("test", "caveat"))
+    };
+    assert!(token.verify(&example_key(), verifier_at(at(0))).is_ok());
+    assert!(token.verify(&example_key(), verifier_at(expiry_time - Duration::new(0, 1))).is_ok());
+    assert!(token.verify(&example_key(), verifier_at(expiry_time)).is_err());
+    assert!(token.verify(&example_key(), verifier_at(at(1_893_456_001))).is_err());
+
+    // Expired caveats are violated, and other caveats left to other verifiers
+    let expiry = Expiry::with_clock(FixedClock(expiry_time));
+    assert_eq!(Outcome::Violated(String::from("expired at 2030-01-01T00:00:00Z")),
+               expiry.check_first_party(&caveat.caveat_id));
+    assert_eq!(Outcome::Satisfied,
+               Expiry::with_clock(FixedClock(at(0))).check_first_party(&caveat.caveat_id));
+    assert_eq!(Outcome::Unrecognized, expiry.check_first_party(b"test = caveat"));
+    assert_eq!(Outcome::Unrecognized, expiry.check_first_party(b"time > 2030-01-01T00:00:00Z"));
+
+    // Linking keeps the reason, as does a KeyedVerifier
+    let error = token.verify_detailed(&example_key(), verifier_at(expiry_time)).unwrap_err();
+    assert_eq!(Some(Outcome::Violated(String::from("expired at 2030-01-01T00:00:00Z"))),
+               error.outcome);
+
+    let keyed = KeyedVerifier::new()
+        .add("time", Expiry::with_clock(FixedClock(expiry_time)))
+        .add("test", verifier::Eq("test", "caveat"));
+    let error = token.verify_detailed(&example_key(), &keyed).unwrap_err();
+    assert_eq!(Some(0), error.caveat_index);
+    assert_eq!(Some(Outcome::Violated(String::from("expired at 2030-01-01T00:00:00Z"))),
+               error.outcome);
+
+    // Without another verifier the other caveat is not satisfied
+    let expiry_only = Expiry::with_clock(FixedClock(at(0)));
+    assert!(token.verify(&example_key(), &expiry_only).is_err());
+
+    // The system clock is used by default
+    let now = SystemTime::now();
+    let hour = Duration::from_secs(3600);
+    let valid = example_token().add_caveat(&Caveat::expiry(now + hour).unwrap());
+    let expired = example_token().add_caveat(&Caveat::expiry(now - hour).unwrap());
+    assert!(valid.verify(&example_key(), Expiry::new()).is_ok());
+    assert!(expired.verify(&example_key(), Expiry::new()).is_err());
+
+    // Malformed timestamps are never satisfied
+    let malformed = example_token().add_caveat(&Caveat::lt("time", "tomorrow").unwrap());
+    assert!(malformed.verify(&example_key(), Expiry::new()).is_err());
+    assert_eq!(Outcome::Violated(String::from("malformed expiry time \"tomorrow\"")),
+               Expiry::new().check_first_party(b"time < tomorrow"));
+}
+
+#[test]
+fn expiry_timestamps() {
+    fn parse(timestamp: &str) -> SystemTime {
+        expiry::parse_timestamp(timestamp).unwrap()
+    }
+
+    assert_eq!(at(0), parse("1970-01-01T00:00:00Z"));
+    assert_eq!(at(1_893_456_000), parse("2029-12-31T19:00:00-05:00"));
+    assert_eq!(at(1_893_456_000), parse("2030-01-01t05:30:00+05:30"));
+    assert_eq!(at(951_782_400), parse("2000-02-29T00:00:00z"));
+    assert_eq!(at(1) + Duration::from_millis(250), parse("1970-01-01T00:00:01.25Z"));
+    assert_eq!(at(0) - Duration::from_millis(500), parse("1969-12-31T23:59:59.5Z"));
+    assert_eq!(at(1) + Duration::new(0, 123_456_789),
+               parse("1970-01-01T00:00:01.1234567891Z"));
+
+    let malformed = ["",
+                     "2030-01-01",
+                     "2030-01-01T00:00:00",
+                     "2030-01-01 00:00:00Z",
+                     "2030-13-01T00:00:00Z",
+                     "1900-02-29T00:00:00Z",
+                     "2030-01-01T24:00:00Z",
+                     "2030-01-01T00:00:00.Z",
+                     "2030-01-01T00:00:00+0500",
+                     "2030-01-01T00:00:00Z ",
+                     "+030-01-01T00:00:00Z"];
+
+    for timestamp in &malformed {
+        assert_eq!(Err(Error::Timestamp), expiry::parse_timestamp(timestamp), "{:?}", timestamp);
+    }
+
+    assert_eq!("1969-12-31T23:59:59Z",
+               expiry::format_timestamp(at(0) - Duration::from_millis(500)).unwrap());
+    assert_eq!(Err(Error::Timestamp),
+               expiry::format_timestamp(at(253_402_300_800)));
+
+    // Formatting and parsing agree across the whole supported range
+    let earliest = -62_167_219_200i64;
+    let mut seconds = earliest;
+
+    while seconds < 253_402_300_800 {
+        let time = if seconds < 0 {
+            at(0) - Duration::from_secs(-seconds as u64)
+        } else {
+            at(seconds as u64)
+        };
+
+        assert_eq!(time, parse(&expiry::format_timestamp(time).unwrap()));
+        seconds += 3_456_789;
+    }
+
+    assert_eq!("0000-01-01T00:00:00Z",
+               expiry::format_timestamp(at(0) - Duration::from_secs(-earliest as u64)).unwrap());
+}
+
+#[test]
+fn v2_signature_matches_v1() {
+    let token = V2Token::new(&example_key(), example_id(), Some(example_uri()))
+        .add_caveat(&example_first_party_caveat());
+
+    assert_eq!(EXPECTED_TAG_WITH_FIRST_PARTY_CAVEATS, token.tag)
+}
+
+#[test]
+fn v2_binary_serialization() {
+    let token = V2Token::new(&example_key(), example_id(), Some(example_uri()))
+        .add_caveat(&example_first_party_caveat());
+
+    assert_eq!(example_v2_serialized_with_first_party_caveats(),
+               token.serialize().unwrap());
+}
+
+fn check_streaming_serialization<T: Token>(token: &T, encoding: Encoding, expected: &[u8]) {
+    assert_eq!(expected.len(), token.serialized_len(encoding).unwrap());
+
+    let mut written = Vec::new();
+    token.serialize_into(&mut written, encoding).unwrap();
+    assert_eq!(expected, &written[..]);
+
+    let mut buffer = Vec::from("HTTP/1.1 200 OK\r\n\r\n");
+    token.serialize_into_vec(&mut buffer, encoding).unwrap();
+    assert_eq!(b"HTTP/1.1 200 OK\r\n\r\n", &buffer[..19]);
+    assert_eq!(expected, &buffer[19..]);
+
+    // Writers that run out of space report an error
+    let mut short_buffer = vec![0u8; expected.len() - 1];
+    assert_eq!(Err(Error::Io),
+               token.serialize_into(&mut &mut short_buffer[..], encoding));
+}
+
+#[test]
+fn streaming_serialization() {
+    // Cover every length of the final Base64 group
+    for length in 0..6 {
+        let token = V1Token::new(&example_key(), vec![b'x'; length], Some(example_uri()))
+            .add_caveat(&example_first_party_caveat())
+            .add_caveat(&example_third_party_caveat());
+        let v2_token = V2Token::from(V1Token::deserialize(token.serialize().unwrap()).unwrap());
+
+        let raw = URL_SAFE_NO_PAD.decode(token.serialize().unwrap()).unwrap();
+        let v2_raw = v2_token.serialize().unwrap();
+
+        check_streaming_serialization(&token, Encoding::Base64, &token.serialize().unwrap());
+        check_streaming_serialization(&token, Encoding::Raw, &raw);
+        check_streaming_serialization(&v2_token,
+                                      Encoding::Base64,
+                                      URL_SAFE_NO_PAD.encode(&v2_raw).as_bytes());
+        check_streaming_serialization(&v2_token, Encoding::Raw, &v2_raw);
+    }
+
+    assert_eq!(example_serialized_with_first_party_caveats(),
+               example_token().add_caveat(&example_first_party_caveat()).serialize().unwrap());
+}
+
+#[test]
+fn streaming_serialization_checks_packet_lengths() {
+    let token = V1Token::new(&example_key(), vec![b'x'; 65535], None);
+    let mut written = Vec::new();
+
+    assert_eq!(Err(Error::PacketLength), token.serialized_len(Encoding::Raw));
+    assert_eq!(Err(Error::PacketLength),
+               token.serialize_into(&mut written, Encoding::Base64));
+    assert!(written.is_empty());
+
+    // V2 has no such limit, and needs a multi-byte length
+    let v2_token = V2Token::new(&example_key(), vec![b'x'; 65535], None);
+    assert_eq!(v2_token.serialize().unwrap().len(),
+               v2_token.serialized_len(Encoding::Raw).unwrap());
+    assert_eq!(vec![b'x'; 65535],
+               V2Token::deserialize(v2_token.serialize().unwrap()).unwrap().identifier);
+}
+
+#[test]
+fn v2_binary_deserialization() {
+    let token = V2Token::deserialize(example_v2_serialized_with_first_party_caveats()).unwrap();
+
+    assert_eq!(example_uri(), token.location.clone().unwrap());
+    assert_eq!(example_id(), token.identifier);
+    assert_eq!(Vec::from("test = caveat"), token.caveats[0].caveat_id);
+    assert_eq!(EXPECTED_TAG_WITH_FIRST_PARTY_CAVEATS, token.tag);
+
+    assert!(token.authenticate_without_verifying(&example_key()).is_ok());
+}
+
+#[test]
+fn v2_third_party_caveat_round_trip() {
+    let token = V2Token::new(&example_key(), example_id(), None)
+        .add_caveat(&example_first_party_caveat())
+        .add_caveat(&example_third_party_caveat());
+
+    let parsed_token = V2Token::deserialize(token.serialize().unwrap()).unwrap();
+    let third_party_caveat = &parsed_token.caveats[1];
+
+    assert_eq!(None, parsed_token.location);
+    assert_eq!(third_party_caveat.caveat_id,
+               example_third_party_caveat_id());
+    assert_eq!(third_party_caveat.caveat_location,
+               Some(example_third_party_caveat_location()));
+    assert_eq!(third_party_caveat.verification_id,
+               token.caveats[1].verification_id);
+    assert_eq!(token.tag, parsed_token.tag);
+}
+
+#[test]
+fn v2_rejects_malformed_tokens() {
+    let serialized = example_v2_serialized_with_first_party_caveats();
+
+    let mut wrong_version = serialized.clone();
+    wrong_version[0] = 0x01;
+    assert_eq!(Err(Error::UnknownVersion),
+               V2Token::deserialize(wrong_version).map(|_| ()));
+
+    let truncated = serialized[..serialized.len() - 1].to_vec();
+    assert_eq!(Err(Error::PacketLength),
+               V2Token::deserialize(truncated).map(|_| ()));
+
+    let without_signature = serialized[..serialized.len() - 34].to_vec();
+    assert_eq!(Err(Error::MissingSignature),
+               V2Token::deserialize(without_signature).map(|_| ()));
+}
+
+#[test]
+fn v2_json_round_trip() {
+    let token = V2Token::new(&example_key(), example_id(), Some(example_uri()))
+        .add_caveat(&example_first_party_caveat())
+        .add_caveat(&example_third_party_caveat());
+
+    let parsed_token = V2Token::deserialize_json(token.serialize_json().unwrap()).unwrap();
+
+    assert_eq!(token.location, parsed_token.location);
+    assert_eq!(token.identifier, parsed_token.identifier);
+    assert_eq!(token.caveats[0].caveat_id, parsed_token.caveats[0].caveat_id);
+    assert_eq!(token.caveats[1].verification_id,
+               parsed_token.caveats[1].verification_id);
+    assert_eq!(token.caveats[1].caveat_location,
+               parsed_token.caveats[1].caveat_location);
+    assert_eq!(token.tag, parsed_token.tag);
+}
+
+#[test]
+fn v2_json_serialization_from_v1() {
+    let token = V2Token::from(example_token().add_caveat(&example_first_party_caveat()));
+    let json = String::from_utf8(token.serialize_json().unwrap()).unwrap();
+
+    assert_eq!("{\"c\":[{\"i\":\"test = caveat\"}],\"i\":\"we used our secret key\",\
+                \"l\":\"http://mybank/\",\
+                \"s64\":\"GXusegRK8zMyhluSZuJtSTvdZopmDkTYjOGpmMI9vWc\",\"v\":2}",
+               json);
+}
+
+#[test]
+fn v2_json_base64_fields() {
+    let token = V2Token::new(&example_key(), vec![0xff, 0xfe, 0x00], None)
+        .add_caveat(&Caveat::first_party(vec![0xc3, 0x28]));
+    let json = String::from_utf8(token.serialize_json().unwrap()).unwrap();
+
+    assert!(json.contains("\"i64\":\"__4A\""));
+    assert!(json.contains("\"i64\":\"wyg\""));
+
+    let parsed_token = V2Token::deserialize_json(json.into_bytes()).unwrap();
+    assert_eq!(vec![0xff, 0xfe, 0x00], parsed_token.identifier);
+    assert_eq!(vec![0xc3, 0x28], parsed_token.caveats[0].caveat_id);
+    assert!(parsed_token.authenticate_without_verifying(&example_key()).is_ok());
+}
+
+#[test]
+fn v2_json_deserialization_accepts_standard_base64() {
+    let json = "{\"v\":2,\"l\":\"http://mybank/\",\"i64\":\"d2UgdXNlZCBvdXIgc2VjcmV0IGtleQ==\",\
+                \"c\":[{\"i\":\"test = caveat\"}],\
+                \"s64\":\"GXusegRK8zMyhluSZuJtSTvdZopmDkTYjOGpmMI9vWc=\"}";
+    let token = V2Token::deserialize_json(Vec::from(json)).unwrap();
+
+    assert_eq!(example_id(), token.identifier);
+    assert_eq!(EXPECTED_TAG_WITH_FIRST_PARTY_CAVEATS, token.tag);
+}
+
+#[test]
+fn v2_json_rejects_malformed_tokens() {
+    assert_eq!(Err(Error::Json),
+               V2Token::deserialize_json(Vec::from("not json")).map(|_| ()));
+    assert_eq!(Err(Error::UnknownVersion),
+               V2Token::deserialize_json(Vec::from("{\"v\":1}")).map(|_| ()));
+    assert_eq!(Err(Error::MissingIdentifier),
+               V2Token::deserialize_json(Vec::from("{\"v\":2}")).map(|_| ()));
+    assert_eq!(Err(Error::Json),
+               V2Token::deserialize_json(Vec::from("{\"v\":2,\"i\":\"a\",\"i64\":\"YQ\"}"))
+                   .map(|_| ()));
+    assert_eq!(Err(Error::MissingSignature),
+               V2Token::deserialize_json(Vec::from("{\"v\":2,\"i\":\"a\"}")).map(|_| ()));
+}
+
+#[test]
+fn format_detection_v1() {
+    let url_safe = example_serialized_with_first_party_caveats();
+    let standard = STANDARD.encode(URL_SAFE_NO_PAD.decode(&url_safe).unwrap()).into_bytes();
+
+    for serialized in [url_safe, standard] {
+        let macaroon = Macaroon::deserialize(serialized).unwrap();
+        assert_eq!(Format::V1, macaroon.format());
+
+        let token = macaroon.into_v1();
+        assert_eq!(example_id(), token.identifier);
+        assert_eq!(EXPECTED_TAG_WITH_FIRST_PARTY_CAVEATS, token.tag);
+    }
+}
+
+#[test]
+fn format_detection_v2() {
+    let binary = example_v2_serialized_with_first_party_caveats();
+    let encoded = URL_SAFE_NO_PAD.encode(&binary).into_bytes();
+
+    for serialized in [binary, encoded] {
+        let macaroon = Macaroon::deserialize(serialized).unwrap();
+        assert_eq!(Format::V2, macaroon.format());
+
+        let token = macaroon.into_v2();
+        assert_eq!(example_id(), token.identifier);
+        assert_eq!(EXPECTED_TAG_WITH_FIRST_PARTY_CAVEATS, token.tag);
+    }
+}
+
+#[test]
+fn format_detection_v2_json() {
+    let token = V2Token::new(&example_key(), example_id(), Some(example_uri()))
+        .add_caveat(&example_first_party_caveat());
+
+    let mut serialized = token.serialize_json().unwrap();
+    serialized.push(b'\n');
+
+    let macaroon = Macaroon::deserialize(serialized).unwrap();
+    assert_eq!(Format::V2J, macaroon.format());
+    assert_eq!(EXPECTED_TAG_WITH_FIRST_PARTY_CAVEATS, macaroon.into_v2().tag);
+}
+
+#[test]
+fn format_detection_rejects_unknown_formats() {
+    assert_eq!(Err(Error::UnknownVersion),
+               Macaroon::deserialize(Vec::from("  ")).map(|_| ()));
+    assert_eq!(Err(Error::Base64),
+               Macaroon::deserialize(Vec::from("not a token!")).map(|_| ()));
+    assert_eq!(Err(Error::UnknownVersion),
+               Macaroon::deserialize(Vec::from("c29tZXRoaW5nIGVsc2U")).map(|_| ()));
+}
+
+#[test]
+fn third_party_caveat_id_round_trip() {
+    let caveat = example_encrypted_third_party_caveat();
+    assert!(caveat.is_third_party());
+
+    let info = discharge::decode_caveat_id(&example_shared_key(), &caveat.caveat_id).unwrap();
+    assert_eq!(caveat.caveat_id, info.caveat_id);
+    assert_eq!(RootKey::from(example_caveat_key()), info.caveat_key);
+    assert_eq!(Vec::from("user = alice"), info.predicate);
+
+    assert_eq!(Err(Error::Decryption),
+               discharge::decode_caveat_id(&invalid_key(), &caveat.caveat_id)
+                   .map(|_| ()));
+    assert_eq!(Err(Error::Decryption),
+               discharge::decode_caveat_id(&example_shared_key(), b"too short").map(|_| ()));
+}
+
+#[test]
+fn minting_discharges() {
+    let caveat = example_encrypted_third_party_caveat();
+    let info = discharge::decode_caveat_id(&example_shared_key(), &caveat.caveat_id).unwrap();
+
+    let discharge: V1Token = info.discharge(Some(example_third_party_caveat_location()));
+    assert_eq!(caveat.caveat_id, discharge.identifier);
+    assert_eq!(Some(example_third_party_caveat_location()), discharge.location);
+    assert!(discharge.authenticate_without_verifying(&RootKey::from(example_caveat_key())).is_ok());
+}
+
+#[test]
+fn collecting_discharges() {
+    let nested_shared_key = RootKey::from("a key shared between auth.mybank and 2fa.mybank");
+    let nested_caveat = discharge::third_party_caveat(&nested_shared_key,
+                                                      Vec::from("another caveat key"),
+                                                      Vec::from("2fa = ok"),
+                                                      Vec::from("http://2fa.mybank/"))
+        .unwrap();
+    let caveat = example_encrypted_third_party_caveat();
+
+    let token = example_token()
+        .add_caveat(&example_first_party_caveat())
+        .add_caveat(&caveat)
+        .add_caveat(&caveat);
+
+    let mut requested: Vec<Vec<u8>> = Vec::new();
+
+    let discharges = discharge::collect_discharges(&token, |caveat| {
+            requested.push(caveat.caveat_location.clone().unwrap());
+
+            match &caveat.caveat_location.clone().unwrap()[..] {
+                b"http://auth.mybank/" => {
+                    let info = discharge::decode_caveat_id(&example_shared_key(),
+                                                           &caveat.caveat_id)?;
+                    let discharge: V1Token = info.discharge(None);
+                    Ok(discharge.add_caveat(&nested_caveat))
+                }
+                _ => {
+                    let info = discharge::decode_caveat_id(&nested_shared_key,
+                                                           &caveat.caveat_id)?;
+                    Ok(info.discharge(None))
+                }
+            }
+        })
+        .unwrap();
+
+    assert_eq!(vec![example_third_party_caveat_location(), Vec::from("http://2fa.mybank/")],
+               requested);
+    assert_eq!(2, discharges.len());
+    assert_eq!(caveat.caveat_id, discharges[0].identifier);
+    assert_eq!(nested_caveat.caveat_id, discharges[1].identifier);
+
+    // The one discharge collected for the repeated caveat satisfies both
+    let discharges: Vec<V1Token> = discharges.iter()
+        .map(|discharge| token.prepare_for_request(discharge))
+        .collect();
+    assert!(token.verify_with_discharges(&example_key(), Func(verify_caveat), &discharges)
+        .is_ok());
+}
+
+#[test]
+fn collecting_discharges_rejects_mismatched_discharges() {
+    let token = example_token().add_caveat(&example_encrypted_third_party_caveat());
+    let result = discharge::collect_discharges(&token, |_| Ok(example_token()));
+
+    assert_eq!(Err(Error::InvalidDischarge), result.map(|_| ()));
+}
+
+#[test]
+fn binding_discharges() {
+    let authorizing = example_token().add_caveat(&example_first_party_caveat());
+    let discharge = example_token();
+
+    let bound_discharge = authorizing.prepare_for_request(&discharge);
+    assert_eq!(discharge.identifier, bound_discharge.identifier);
+    assert_eq!(EXPECTED_BOUND_TAG, bound_discharge.tag);
+}
+
+#[test]
+fn verifying_bound_discharges() {
+    let caveat = example_encrypted_third_party_caveat();
+    let authorizing = example_token().add_caveat(&caveat);
+    let other_authorizing = example_token().add_caveat(&example_first_party_caveat());
+
+    let info = discharge::decode_caveat_id(&example_shared_key(), &caveat.caveat_id).unwrap();
+    let discharge: V1Token = info.discharge(None);
+    let discharge = discharge.add_caveat(&example_first_party_caveat());
+    let bound_discharge = authorizing.prepare_for_request(&discharge);
+
+    let verifier = Func(verify_caveat);
+    let wrong_verifier = Func(verify_wrong_value);
+
+    assert!(bound_discharge.verify_discharge(&authorizing, &info.caveat_key, &verifier)
+        .is_ok());
+    assert!(bound_discharge.verify_discharge(&authorizing, &info.caveat_key, &wrong_verifier)
+        .is_err(), "checks the discharge's caveats");
+    assert!(discharge.verify_discharge(&authorizing, &info.caveat_key, &verifier)
+        .is_err(), "rejects unbound discharges");
+    assert!(bound_discharge.verify_discharge(&other_authorizing, &info.caveat_key, &verifier)
+        .is_err(), "rejects discharges bound to another token");
+    assert!(bound_discharge.verify_discharge(&authorizing, &invalid_key(), &verifier)
+        .is_err(), "rejects discharges with the wrong caveat key");
+}
+
+fn example_discharge(caveat: &Caveat) -> V1Token {
+    let info = discharge::decode_caveat_id(&example_shared_key(), &caveat.caveat_id).unwrap();
+    info.discharge(Some(example_third_party_caveat_location()))
+}
+
+#[test]
+fn verifying_with_discharges() {
+    let caveat = example_encrypted_third_party_caveat();
+    let token = example_token()
+        .add_caveat(&example_first_party_caveat())
+        .add_caveat(&caveat);
+
+    let discharge = example_discharge(&caveat).add_caveat(&example_first_party_caveat());
+    let bound_discharge = token.prepare_for_request(&discharge);
+
+    let verifier = Func(verify_caveat);
+    assert!(token.verify_with_discharges(&example_key(), &verifier, &[bound_discharge])
+        .is_ok());
+
+    let bound_discharge = token.prepare_for_request(&discharge);
+    assert_eq!(Err(Error::VerificationFailed),
+               token.verify_with_discharges(&invalid_key(), &verifier, &[bound_discharge]));
+
+    let bound_discharge = token.prepare_for_request(&discharge);
+    assert_eq!(Err(Error::FirstPartyCaveatFailed),
+               token.verify_with_discharges(&example_key(),
+                                            Func(verify_wrong_value),
+                                            &[bound_discharge]));
+
+    assert_eq!(Err(Error::VerificationFailed),
+               token.verify_with_discharges(&example_key(), &verifier, &[discharge]),
+               "rejects unbound discharges");
+
+    assert_eq!(Err(Error::MissingDischarge),
+               token.verify_with_discharges(&example_key(), &verifier, &[]));
+}
+
+#[test]
+fn verification_errors_name_the_failing_caveat() {
+    let token = example_token()
+        .add_caveat(&example_first_party_caveat_different_prefix())
+        .add_caveat(&example_first_party_caveat());
+
+    let error = token.verify_detailed(&invalid_key(), Func(verify_caveat)).unwrap_err();
+    assert_eq!(FailureKind::Signature, error.kind);
+    assert_eq!(None, error.caveat_index);
+    assert_eq!(Error::VerificationFailed, error.error);
+
+    let error = token.verify_detailed(&example_key(), Func(verify_wrong_value)).unwrap_err();
+    assert_eq!(FailureKind::FirstParty, error.kind);
+    assert_eq!(Some(1), error.caveat_index);
+    assert_eq!(Some(Vec::from("test = caveat")), error.predicate);
+    assert_eq!(Error::FirstPartyCaveatFailed, error.error);
+    assert_eq!(Error::VerificationFailed, error.opaque());
+    assert_eq!("first-party caveat failed at caveat 1 (\"test = caveat\"): a first-party \
+                caveat failed to verify: caveat not recognized",
+               error.to_string());
+    assert_eq!(Err(Error::FirstPartyCaveatFailed),
+               token.verify(&example_key(), Func(verify_wrong_value)));
+
+    // Forged tokens report the signature, whatever their caveats say
+    let mut forged = token.add_caveat(&Caveat::first_party(Vec::from("test = wrong")));
+    forged.caveats.remove(1);
+    let error = forged.verify_detailed(&example_key(), Func(verify_caveat)).unwrap_err();
+    assert_eq!(FailureKind::Signature, error.kind);
+
+    let discharges: [V1Token; 0] = [];
+    let error = forged.verify_with_discharges_detailed(&example_key(),
+                                                       Func(verify_caveat),
+                                                       &discharges)
+        .unwrap_err();
+    assert_eq!(FailureKind::Signature, error.kind);
+}
+
+// Understands "account = ..." caveats, and explains why they fail
+struct AccountVerifier(&'static str);
+
+impl CaveatVerifier for AccountVerifier {
+    fn check_first_party(&self, caveat: &[u8]) -> Outcome {
+        match Predicate::parse(caveat) {
+            Ok(Predicate::Eq(ref key, ref account)) if key == "account" => {
+                if account == self.0 {
+                    Outcome::Satisfied
+                } else {
+                    Outcome::Violated(format!("token is for account {}, not {}", account, self.0))
+                }
+            }
+            _ => Outcome::Unrecognized,
+        }
+    }
+}
+
+// Satisfied by the example first-party caveat
+struct TestVerifier;
+
+impl Verifier for TestVerifier {
+    fn verify_first_party(&self, caveat: &[u8]) -> bool {
+        caveat == b"test = caveat"
+    }
+}
+
+#[test]
+fn verifying_with_outcomes() {
+    let token = example_token().add_caveat(&Caveat::eq("account", "3735928559").unwrap());
+
+    assert!(token.verify(&example_key(), AccountVerifier("3735928559")).is_ok());
+
+    let error = token.verify_detailed(&example_key(), AccountVerifier("1")).unwrap_err();
+    assert_eq!(Error::FirstPartyCaveatFailed, error.error);
+    assert_eq!(Some(Outcome::Violated("token is for account 3735928559, not 1".to_owned())),
+               error.outcome);
+    assert!(error.to_string().ends_with(": token is for account 3735928559, not 1"));
+
+    // Caveats the verifier doesn't understand fail closed
+    let token = token.add_caveat(&example_first_party_caveat());
+    let error = token.verify_detailed(&example_key(), AccountVerifier("3735928559")).unwrap_err();
+    assert_eq!(Some(1), error.caveat_index);
+    assert_eq!(Some(Outcome::Unrecognized), error.outcome);
+    assert!(error.to_string().ends_with(": caveat not recognized"));
+
+    // Eq knows the caveats with its tag, but boolean verifiers can't tell
+    // violated caveats from unrecognized ones
+    assert_eq!(Outcome::Satisfied,
+               verifier::Eq("test", "caveat").check_first_party(b"test = caveat"));
+    assert_eq!(Outcome::Violated("test is not caveat".to_owned()),
+               verifier::Eq("test", "caveat").check_first_party(b"test = wrong"));
+    assert_eq!(Outcome::Unrecognized,
+               verifier::Eq("test", "caveat").check_first_party(b"tester = caveat"));
+
+    let error = token.verify_detailed(&example_key(), Func(verify_wrong_value)).unwrap_err();
+    assert_eq!(Some(Outcome::Unrecognized), error.outcome);
+
+    // Other Verifiers are adapted with Checked
+    let token = example_token().add_caveat(&example_first_party_caveat());
+    assert!(token.verify(&example_key(), Checked(TestVerifier)).is_ok());
+    assert_eq!(Outcome::Unrecognized, Checked(TestVerifier).check_first_party(b"test = wrong"));
+
+    // Signature failures have no outcome
+    let error = token.verify_detailed(&invalid_key(), AccountVerifier("3735928559")).unwrap_err();
+    assert_eq!(None, error.outcome);
+}
+
+fn claim(caveat: &str, verifier: &str) -> Claim {
+    Claim {
+        caveat: Vec::from(caveat),
+        verifier: verifier.to_owned(),
+    }
+}
+
+#[test]
+fn keyed_verification_reports_coverage() {
+    let caveat = example_encrypted_third_party_caveat();
+    let token = example_token()
+        .add_caveat(&example_first_party_caveat())
+        .add_caveat(&caveat)
+        .add_caveat(&Caveat::eq("account", "3735928559").unwrap());
+    let discharge = example_discharge(&caveat)
+        .add_caveat(&example_first_party_caveat_different_prefix());
+    let discharges = [token.prepare_for_request(&discharge)];
+
+    let verifier = KeyedVerifier::new()
+        .add("test", verifier::Eq("test", "caveat"))
+        .add("other", verifier::Eq("other", "test"))
+        .add("account", AccountVerifier("3735928559"));
+
+    let report = verifier.verify(&token, &example_key(), &discharges).unwrap();
+    assert_eq!(vec![claim("test = caveat", "test"),
+                    claim("other = test", "other"),
+                    claim("account = 3735928559", "account")],
+               report.claims);
+
+    // A KeyedVerifier is itself a verifier, which can be passed by reference
+    // or shared like any other
+    assert!(token.verify_with_discharges(&example_key(), &verifier, &discharges).is_ok());
+
+    let verifier = Rc::new(verifier);
+    assert!(token.verify_with_discharges(&example_key(), verifier.clone(), &discharges).is_ok());
+
+    let verifier: Box<dyn CaveatVerifier> = Box::new(Rc::try_unwrap(verifier).ok().unwrap());
+    assert!(token.verify_with_discharges(&example_key(), &verifier, &discharges).is_ok());
+    assert!(token.verify_with_discharges(&example_key(), verifier, &discharges).is_ok());
+}
+
+#[test]
+fn keyed_verification_fails_closed() {
+    let token = example_token()
+        .add_caveat(&example_first_party_caveat())
+        .add_caveat(&example_first_party_caveat_different_prefix());
+
+    // Verifiers only see caveats with their key, so permissive verifiers
+    // can't satisfy caveats meant for others
+    let verifier = KeyedVerifier::new()
+        .add("test", Func(|_: &str| true))
+        .add("account", Func(|_: &str| true));
+
+    let error = verifier.verify(&token, &example_key(), &[]).unwrap_err();
+    assert_eq!(Some(1), error.caveat_index);
+    assert_eq!(Some(Outcome::Unrecognized), error.outcome);
+    assert_eq!(Outcome::Unrecognized, verifier.check_first_party(b"other = test"));
+    assert_eq!(Outcome::Unrecognized, verifier.check_first_party(b"tester = test"));
+
+    let verifier = verifier.add("other", AccountVerifier("3735928559"));
+    let error = verifier.verify(&token, &example_key(), &[]).unwrap_err();
+    assert_eq!(Some(1), error.caveat_index);
+    assert_eq!(Some(Outcome::Unrecognized), error.outcome);
+
+    // Adding a verifier for a key replaces the previous one
+    let verifier = verifier.add("other", verifier::Eq("other", "test"));
+    assert_eq!(2, verifier.verify(&token, &example_key(), &[]).unwrap().claims.len());
+    assert!(verifier.verify(&token, &invalid_key(), &[]).is_err());
+}
+
+#[test]
+fn combined_verifiers_express_policies() {
+    let token = example_token()
+        .add_caveat(&Caveat::eq("tenant", "acme").unwrap())
+        .add_caveat(&Caveat::eq("ip", "10.0.0.7").unwrap());
+
+    // Each caveat must be one of the tenant's, and IP caveats must also pass
+    // the address check without being on the blocklist
+    let ip_check = AllOf::new()
+        .with(Func(|caveat: &str| caveat.starts_with("ip = 10.")))
+        .with(Not::with_prefix("ip = ", verifier::Eq("ip", "10.0.0.1")));
+    let policy = AnyOf::new()
+        .with(verifier::Eq("tenant", "acme"))
+        .with(Prefix("ip = ", ip_check));
+
+    assert!(token.verify(&example_key(), &policy).is_ok());
+    assert!(token.verify(&invalid_key(), &policy).is_err());
+
+    let blocked = token.add_caveat(&Caveat::eq("ip", "10.0.0.1").unwrap());
+    let error = blocked.verify_detailed(&example_key(), &policy).unwrap_err();
+    assert_eq!(Some(2), error.caveat_index);
+    assert_eq!(Some(Outcome::Violated("negated caveat holds".to_owned())), error.outcome);
+
+    let other_tenant = example_token().add_caveat(&Caveat::eq("tenant", "umbrella").unwrap());
+    assert_eq!(Err(Error::FirstPartyCaveatFailed),
+               other_tenant.verify(&example_key(), &policy));
+
+    // Combinators hold boxed verifiers of any kind, including borrowed ones
+    let boxed: Box<dyn CaveatVerifier> = Box::new(verifier::Eq("tenant", "acme"));
+    let policy = AnyOf(vec![boxed, Box::new((&policy).link(Expiry::new()))]);
+    assert!(token.verify(&example_key(), &policy).is_ok());
+}
+
+#[test]
+fn combined_verifiers_fail_closed() {
+    let caveat = b"test = caveat";
+
+    assert!(!AllOf::new().verify_first_party(caveat));
+    assert!(!AnyOf::new().verify_first_party(caveat));
+    assert!(AllOf::new().with(Func(|_: &str| true)).verify_first_party(caveat));
+    assert!(!AllOf::new()
+        .with(Func(|_: &str| true))
+        .with(Func(|_: &str| false))
+        .verify_first_party(caveat));
+
+    // Prefix and Not only pass on caveats they cover, and Not never accepts
+    // third-party caveats
+    assert!(!Prefix("other ", Func(|_: &str| true)).verify_first_party(caveat));
+    assert!(Prefix("test ", verifier::Eq("test", "caveat")).verify_first_party(caveat));
+    assert!(!Not::with_prefix("test ", verifier::Eq("test", "caveat")).verify_first_party(caveat));
+    assert!(Not::with_prefix("test ", verifier::Eq("test", "wrong")).verify_first_party(caveat));
+    assert!(!Not::with_prefix("other ", Func(|_: &str| false)).verify_first_party(caveat));
+    assert!(!Not::with_prefix("test ", Func(|_: &str| false)).verify_third_party(caveat));
+    assert_eq!(Outcome::Unrecognized,
+               Prefix("other ", Func(|_: &str| true)).check_first_party(caveat));
+}
+
+#[test]
+fn combined_verifiers_propagate_outcomes() {
+    let caveat = b"test = caveat";
+    let violated = || Outcome::Violated("test is not wrong".to_owned());
+    let satisfied = || verifier::Eq("test", "caveat");
+    let wrong = || verifier::Eq("test", "wrong");
+    let unrecognized = || verifier::Eq("other", "caveat");
+
+    // A violation wins in AllOf, and is reported by AnyOf when nothing
+    // satisfies the caveat
+    assert_eq!(violated(),
+               AllOf::new().with(unrecognized()).with(wrong()).check_first_party(caveat));
+    assert_eq!(violated(), AllOf::new().with(satisfied()).with(wrong()).check_first_party(caveat));
+    assert_eq!(Outcome::Unrecognized,
+               AllOf::new().with(satisfied()).with(unrecognized()).check_first_party(caveat));
+    assert_eq!(Outcome::Satisfied,
+               AnyOf::new().with(wrong()).with(satisfied()).check_first_party(caveat));
+    assert_eq!(violated(),
+               AnyOf::new().with(unrecognized()).with(wrong()).check_first_party(caveat));
+    assert_eq!(Outcome::Unrecognized, AnyOf::new().with(unrecognized()).check_first_party(caveat));
+
+    // Linking works the same way as AnyOf
+    assert_eq!(Outcome::Satisfied, wrong().link(satisfied()).check_first_party(caveat));
+    assert_eq!(violated(), unrecognized().link(wrong()).check_first_party(caveat));
+    assert_eq!(violated(), wrong().link(unrecognized()).check_first_party(caveat));
+
+    // Not swaps satisfied and violated caveats, but leaves unrecognized ones
+    assert_eq!(Outcome::Violated("negated caveat holds".to_owned()),
+               Not::with_prefix("test ", satisfied()).check_first_party(caveat));
+    assert_eq!(Outcome::Satisfied, Not::with_prefix("test ", wrong()).check_first_party(caveat));
+    assert_eq!(Outcome::Unrecognized,
+               Not::with_prefix("test ", unrecognized()).check_first_party(caveat));
+
+    // Prefix passes on the outcome of caveats it covers
+    assert_eq!(violated(), Prefix("test ", wrong()).check_first_party(caveat));
+
+    // Any CaveatVerifier can be combined
+    let expiry = Expiry::with_clock(FixedClock(at(1_893_456_000)));
+    let caveat = b"time < 2030-01-01T00:00:00Z";
+    assert_eq!(Outcome::Violated("expired at 2030-01-01T00:00:00Z".to_owned()),
+               AnyOf::new().with(satisfied()).with(&expiry).check_first_party(caveat));
+    assert_eq!(Outcome::Satisfied,
+               Not::with_prefix("time ", &expiry).check_first_party(caveat));
+}
+
+#[test]
+fn verifying_extracts_attributes() {
+    let caveat = example_encrypted_third_party_caveat();
+    let token = example_token()
+        .add_caveat(&Caveat::eq("tenant", "acme").unwrap())
+        .add_caveat(&Caveat::is_in("scope", &["read", "write", "admin"]).unwrap())
+        .add_caveat(&Caveat::lt("uid", "100").unwrap())
+        .add_caveat(&caveat);
+    let discharge = example_discharge(&caveat)
+        .add_caveat(&Caveat::eq("uid", "42").unwrap())
+        .add_caveat(&Caveat::is_in("scope", &["write", "read"]).unwrap());
+    let discharges = [token.prepare_for_request(&discharge)];
+
+    let verifier = KeyedVerifier::new()
+        .add("tenant", verifier::Eq("tenant", "acme"))
+        .add("scope", Func(|_: &str| true))
+        .add("uid", Func(|_: &str| true));
+    let attributes = token.verify_attributes(&example_key(), verifier, &discharges).unwrap();
+
+    assert_eq!(Some("acme"), attributes.get("tenant"));
+    assert_eq!(Some(42), attributes.parse::<u64>("uid"));
+    assert_eq!(None, attributes.parse::<u64>("tenant"));
+    assert_eq!(None, attributes.get("scope"));
+    assert_eq!(vec!["read", "write"],
+               attributes.values("scope").unwrap().iter().collect::<Vec<_>>());
+    assert!(attributes.allows("scope", "write"));
+    assert!(!attributes.allows("scope", "admin"));
+    assert_eq!(3, attributes.iter().count());
+
+    // Disjoint caveats leave no values rather than either of them
+    let token = token.add_caveat(&Caveat::eq("tenant", "umbrella").unwrap());
+    let verifier = Func(|_: &str| true);
+    let discharges = [token.prepare_for_request(&discharge)];
+    let attributes = token.verify_attributes(&example_key(), &verifier, &discharges).unwrap();
+    assert!(attributes.values("tenant").unwrap().is_empty());
+    assert_eq!(None, attributes.get("tenant"));
+
+    let error = token.verify_attributes(&invalid_key(), &verifier, &discharges).unwrap_err();
+    assert_eq!(FailureKind::Signature, error.kind);
+}
+
+// Drives a future to completion on the current thread
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+// Fails to compile unless the value can be sent to another thread
+fn assert_send<T: Send>(_: T) {}
+
+// An in-memory stand-in for a database of revocations and accounts. Every
+// lookup yields to the executor once before completing.
+#[derive(Default)]
+struct Store {
+    revoked: Vec<&'static str>,
+    active_accounts: Vec<&'static str>,
+    lookups: AtomicUsize,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+struct Lookup<'s> {
+    store: &'s Store,
+    outcome: Option<Outcome>,
+    started: bool,
+}
+
+impl<'s> Future for Lookup<'s> {
+    type Output = Outcome;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Outcome> {
+        let store = self.store;
+
+        if !self.started {
+            self.started = true;
+            let in_flight = store.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            store.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            context.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        store.in_flight.fetch_sub(1, Ordering::SeqCst);
+        Poll::Ready(self.outcome.take().unwrap())
+    }
+}
+
+impl AsyncVerifier for Store {
+    fn check_first_party_async<'a>(&'a self, caveat: &[u8]) -> CheckFuture<'a> {
+        let outcome = match Predicate::parse(caveat) {
+            Ok(Predicate::Eq(ref key, ref id)) if key == "revocation-id" => {
+                if self.revoked.contains(&&id[..]) {
+                    Outcome::Violated(format!("{} has been revoked", id))
+                } else {
+                    Outcome::Satisfied
+                }
+            }
+            Ok(Predicate::Eq(ref key, ref account)) if key == "account-active" => {
+                if self.active_accounts.contains(&&account[..]) {
+                    Outcome::Satisfied
+                } else {
+                    Outcome::Violated(format!("{} is not active", account))
+                }
+            }
+            _ => return Box::pin(future::ready(Outcome::Unrecognized)),
+        };
+
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+
+        Box::pin(Lookup {
+            store: self,
+            outcome: Some(outcome),
+            started: false,
+        })
+    }
+}
+
+#[test]
+fn async_verification_checks_caveats_concurrently() {
+    let store = Store {
+        revoked: vec!["13"],
+        active_accounts: vec!["alice"],
+        ..Store::default()
+    };
+
+    let caveat = example_encrypted_third_party_caveat();
+    let token = example_token()
+        .add_caveat(&Caveat::eq("revocation-id", "42").unwrap())
+        .add_caveat(&Caveat::eq("account-active", "alice").unwrap())
+        .add_caveat(&caveat);
+    let discharge = example_discharge(&caveat)
+        .add_caveat(&Caveat::eq("account-active", "alice").unwrap());
+    let discharges = [token.prepare_for_request(&discharge)];
+
+    assert_eq!(Ok(()),
+               block_on(token.verify_with_discharges_async(&example_key(), &store, &discharges))
+                   .map_err(Error::from));
+
+    // Caveats shared by the token and its discharge are only looked up once,
+    // and the lookups all run at the same time
+    assert_eq!(2, store.lookups.load(Ordering::SeqCst));
+    assert_eq!(2, store.max_in_flight.load(Ordering::SeqCst));
+    assert_eq!(0, store.in_flight.load(Ordering::SeqCst));
+
+    // Verification can run on multithreaded executors
+    assert_send(token.verify_with_discharges_async(&example_key(), &store, &discharges));
+    assert_send(store.check_first_party_async(b"revocation-id = 42"));
+
+    // Synchronous verifiers work too
+    let token = example_token().add_caveat(&example_first_party_caveat());
+    assert_send(token.verify_async(&example_key(), &verifier::Eq("test", "caveat")));
+    assert!(block_on(token.verify_async(&example_key(), &verifier::Eq("test", "caveat"))).is_ok());
+    assert!(block_on(token.verify_async(&example_key(), &verifier::Eq("test", "wrong"))).is_err());
+}
+
+#[test]
+fn async_verification_reports_failures() {
+    let store = Store {
+        revoked: vec!["13"],
+        active_accounts: vec!["alice"],
+        ..Store::default()
+    };
+
+    let token = example_token()
+        .add_caveat(&Caveat::eq("account-active", "alice").unwrap())
+        .add_caveat(&Caveat::eq("revocation-id", "13").unwrap())
+        .add_caveat(&Caveat::eq("account-active", "bob").unwrap());
+
+    let error = block_on(token.verify_async(&example_key(), &store)).unwrap_err();
+    assert_eq!(FailureKind::FirstParty, error.kind);
+    assert_eq!(Some(1), error.caveat_index);
+    assert_eq!(Some(Outcome::Violated(String::from("13 has been revoked"))), error.outcome);
+    assert_eq!(Error::FirstPartyCaveatFailed, error.error);
+    assert_eq!(3, store.lookups.load(Ordering::SeqCst));
+
+    let token = example_token().add_caveat(&example_first_party_caveat());
+    let error = block_on(token.verify_async(&example_key(), &store)).unwrap_err();
+    assert_eq!(Some(Outcome::Unrecognized), error.outcome);
+
+    // Nothing is looked up for forged tokens or missing discharges
+    let store = Store::default();
+    let token = example_token().add_caveat(&Caveat::eq("revocation-id", "42").unwrap());
+    let error = block_on(token.verify_async(&invalid_key(), &store)).unwrap_err();
+    assert_eq!(FailureKind::Signature, error.kind);
+
+    let token = token.add_caveat(&example_encrypted_third_party_caveat());
+    let error = block_on(token.verify_with_discharges_async(&example_key(), &store, &[]))
+        .unwrap_err();
+    assert_eq!(FailureKind::ThirdParty, error.kind);
+    assert_eq!(Error::MissingDischarge, error.error);
+    assert_eq!(0, store.lookups.load(Ordering::SeqCst));
+}
+
+#[test]
+fn verification_errors_describe_discharge_failures() {
+    let caveat = example_encrypted_third_party_caveat();
+    let token = example_token()
+        .add_caveat(&example_first_party_caveat())
+        .add_caveat(&caveat);
+    let discharge = example_discharge(&caveat).add_caveat(&example_first_party_caveat());
+
+    let error = token.verify_with_discharges_detailed(&example_key(), Func(verify_caveat), &[])
+        .unwrap_err();
+    assert_eq!(FailureKind::ThirdParty, error.kind);
+    assert_eq!(Some(1), error.caveat_index);
+    assert_eq!(Some(caveat.caveat_id.clone()), error.predicate);
+    assert_eq!(Error::MissingDischarge, error.error);
+    assert_eq!(None, error.discharge_error);
+
+    let discharges = [token.prepare_for_request(&discharge)];
+    let error = token.verify_with_discharges_detailed(&example_key(),
+                                                      verifier::Eq("test", "wrong"),
+                                                      &discharges)
+        .unwrap_err();
+    assert_eq!(FailureKind::FirstParty, error.kind);
+    assert_eq!(Some(0), error.caveat_index);
+
+    // A failing caveat inside the discharge is reported through the
+    // third-party caveat that required it
+    let discharge = example_discharge(&caveat).add_caveat(&Caveat::eq("user", "alice").unwrap());
+    let discharges = [token.prepare_for_request(&discharge)];
+    let error = token.verify_with_discharges_detailed(&example_key(),
+                                                      verifier::Eq("test", "caveat"),
+                                                      &discharges)
+        .unwrap_err();
+    let discharge_error = error.discharge_error.clone().unwrap();
+    assert_eq!(FailureKind::ThirdParty, error.kind);
+    assert_eq!(Some(1), error.caveat_index);
+    assert_eq!(Error::FirstPartyCaveatFailed, error.error);
+    assert_eq!(FailureKind::FirstParty, discharge_error.kind);
+    assert_eq!(Some(0), discharge_error.caveat_index);
+    assert_eq!(Some(Vec::from("user = alice")), discharge_error.predicate);
+
+    // As is an unbound discharge
+    let error = token.verify_with_discharges_detailed(&example_key(),
+                                                      Func(verify_caveat),
+                                                      &[example_discharge(&caveat)])
+        .unwrap_err();
+    assert_eq!(FailureKind::ThirdParty, error.kind);
+    assert_eq!(FailureKind::Signature, error.discharge_error.unwrap().kind);
+
+    let discharges = [token.prepare_for_request(&example_discharge(&caveat)),
+                      token.prepare_for_request(&example_token())];
+    let error = token.verify_with_discharges_detailed(&example_key(),
+                                                      Func(verify_caveat),
+                                                      &discharges)
+        .unwrap_err();
+    assert_eq!(FailureKind::ThirdParty, error.kind);
+    assert_eq!(None, error.caveat_index);
+    assert_eq!(Error::UnusedDischarge, error.error);
+}
+
+#[test]
+fn verifying_nested_discharges_after_round_trip() {
+    let nested_shared_key = RootKey::from("a key shared between auth.mybank and 2fa.mybank");
+    let nested_caveat_key = RootKey::from("another caveat key");
+    let nested_caveat = discharge::third_party_caveat(&nested_shared_key,
+                                                      nested_caveat_key.as_bytes().to_vec(),
+                                                      Vec::from("2fa = ok"),
+                                                      Vec::from("http://2fa.mybank/"))
+        .unwrap();
+
+    let caveat = example_encrypted_third_party_caveat();
+    let token = V2Token::new(&example_key(), example_id(), None).add_caveat(&caveat);
+    let token = V2Token::deserialize(token.serialize().unwrap()).unwrap();
+
+    let discharge: V2Token = discharge::decode_caveat_id(&example_shared_key(),
+                                                         &caveat.caveat_id)
+        .unwrap()
+        .discharge(None);
+    let discharge = discharge.add_caveat(&nested_caveat);
+    let nested_discharge = V2Token::new(&nested_caveat_key, nested_caveat.caveat_id.clone(), None);
+
+    let discharges = vec![token.prepare_for_request(&nested_discharge),
+                          token.prepare_for_request(&discharge)];
+
+    assert!(token.verify_with_discharges(&example_key(), Func(verify_caveat), &discharges)
+        .is_ok());
+
+    // Binding nested discharges to the discharge rather than the authorizing
+    // token must fail
+    let discharges = vec![discharge.prepare_for_request(&nested_discharge),
+                          token.prepare_for_request(&discharge)];
+
+    assert_eq!(Err(Error::VerificationFailed),
+               token.verify_with_discharges(&example_key(), Func(verify_caveat), &discharges));
+}
+
+#[test]
+fn verifying_repeated_third_party_caveats() {
+    let caveat = example_encrypted_third_party_caveat();
+    let token = example_token()
+        .add_caveat(&caveat)
+        .add_caveat(&example_first_party_caveat())
+        .add_caveat(&caveat);
+    let discharge = example_discharge(&caveat).add_caveat(&example_first_party_caveat());
+
+    let checks = Cell::new(0);
+    let verifier = Func(|predicate: &str| {
+        checks.set(checks.get() + 1);
+        verify_caveat(predicate)
+    });
+
+    // The discharge's caveats are checked once, for both occurrences
+    let discharges = vec![token.prepare_for_request(&discharge)];
+    assert!(token.verify_with_discharges(&example_key(), &verifier, &discharges).is_ok());
+    assert_eq!(2, checks.get());
+
+    // A discharge per occurrence leaves one unused
+    let discharges = vec![token.prepare_for_request(&discharge),
+                          token.prepare_for_request(&discharge)];
+    assert_eq!(Err(Error::UnusedDischarge),
+               token.verify_with_discharges(&example_key(), &verifier, &discharges));
+}
+
+#[test]
+fn verifying_rejects_unused_discharges() {
+    let caveat = example_encrypted_third_party_caveat();
+    let token = example_token().add_caveat(&caveat);
+    let discharges = vec![token.prepare_for_request(&example_discharge(&caveat)),
+                          token.prepare_for_request(&example_token())];
+
+    assert_eq!(Err(Error::UnusedDischarge),
+               token.verify_with_discharges(&example_key(), Func(verify_caveat), &discharges));
+}
+
+#[test]
+fn verifying_rejects_cyclic_discharges() {
+    let caveat = example_encrypted_third_party_caveat();
+    let token = example_token().add_caveat(&caveat);
+
+    // A discharge requiring a discharge for its own caveat id
+    let cyclic_caveat = Caveat::third_party(example_caveat_key(),
+                                            caveat.caveat_id.clone(),
+                                            example_third_party_caveat_location());
+    let discharge = example_discharge(&caveat).add_caveat(&cyclic_caveat);
+    let discharges = vec![token.prepare_for_request(&discharge)];
+
+    assert_eq!(Err(Error::CyclicDischarge),
+               token.verify_with_discharges(&example_key(), Func(verify_caveat), &discharges));
+}
+
+#[test]
+fn verifying_authenticates_before_checking_caveats() {
+    let caveat = example_encrypted_third_party_caveat();
+    let token = example_token()
+        .add_caveat(&example_first_party_caveat())
+        .add_caveat(&caveat);
+    let discharge = example_discharge(&caveat).add_caveat(&example_first_party_caveat());
+
+    let checks = Cell::new(0);
+    let verifier = Func(|caveat: &str| {
+        checks.set(checks.get() + 1);
+        verify_caveat(caveat)
+    });
+
+    let discharges = [token.prepare_for_request(&discharge)];
+    assert!(token.verify_with_discharges(&example_key(), &verifier, &discharges).is_ok());
+    assert_eq!(2, checks.get());
+
+    // Neither a forged token nor a forged discharge is shown to the verifier
+    checks.set(0);
+    assert_eq!(Err(Error::VerificationFailed),
+               token.verify_with_discharges(&invalid_key(), &verifier, &discharges));
+    assert_eq!(0, checks.get());
+
+    assert_eq!(Err(Error::VerificationFailed),
+               token.verify_with_discharges(&example_key(), &verifier, &[discharge]));
+    assert_eq!(0, checks.get());
+}
+
+#[test]
+fn third_party_caveat_authenticates_after_round_trip() {
+    let token = example_token()
+        .add_caveat(&example_first_party_caveat())
+        .add_caveat(&example_third_party_caveat());
+
+    assert!(token.authenticate_without_verifying(&example_key()).is_ok());
+
+    let parsed_token = V1Token::deserialize(token.serialize().unwrap()).unwrap();
+    assert!(parsed_token.authenticate_without_verifying(&example_key()).is_ok());
+    assert!(parsed_token.authenticate_without_verifying(&invalid_key()).is_err());
+}
+
+#[test]
+fn third_party_caveat_with_fixed_nonce() {
+    let token = V1Token::deserialize(fixed_nonce_serialized_with_third_party_caveat()).unwrap();
+    assert_eq!(FIXED_NONCE_TAG_WITH_THIRD_PARTY_CAVEAT, token.tag);
+    assert!(token.authenticate_without_verifying(&libmacaroons_key()).is_ok());
+
+    let discharge = V1Token::new(&RootKey::from(example_caveat_key()),
+                                 example_third_party_caveat_id(),
+                                 Some(example_third_party_caveat_location()))
+        .add_caveat(&Caveat::first_party(Vec::from("time < 2015-01-01T00:00")));
+    assert_eq!(LIBMACAROONS_UNBOUND_DISCHARGE_TAG, discharge.tag);
+
+    let bound_discharge = token.prepare_for_request(&discharge);
+    assert_eq!(fixed_nonce_serialized_bound_discharge(),
+               bound_discharge.serialize().unwrap());
+
+    let parsed_discharge = V1Token::deserialize(fixed_nonce_serialized_bound_discharge()).unwrap();
+    assert!(token.verify_with_discharges(&libmacaroons_key(),
+                                         Func(verify_libmacaroons_caveat),
+                                         &[parsed_discharge])
+        .is_ok());
+}
+
+#[test]
+fn libmacaroons_third_party_discharge_vector() {
+    let discharge = V1Token::new(&RootKey::from(example_caveat_key()),
+                                 example_third_party_caveat_id(),
+                                 Some(example_third_party_caveat_location()))
+        .add_caveat(&Caveat::first_party(Vec::from("time < 2015-01-01T00:00")));
+    assert_eq!(LIBMACAROONS_UNBOUND_DISCHARGE_TAG, discharge.tag);
+    assert_eq!(libmacaroons_serialized_discharge(), discharge.serialize().unwrap());
+
+    // Binding only uses the signature of the authorizing macaroon, which the
+    // README publishes along with the bound discharge's
+    let token = V1Token {
+        identifier: Vec::from("we used our other secret key"),
+        location: Some(example_uri()),
+        caveats: Vec::new(),
+        tag: Signature::new(LIBMACAROONS_TAG_WITH_THIRD_PARTY_CAVEAT),
+    };
+
+    let bound_discharge = token.prepare_for_request(&discharge);
+    assert_eq!(LIBMACAROONS_BOUND_DISCHARGE_TAG, bound_discharge.tag);
+    assert_eq!(libmacaroons_serialized_bound_discharge(),
+               bound_discharge.serialize().unwrap());
+
+    let parsed_discharge = V1Token::deserialize(libmacaroons_serialized_bound_discharge()).unwrap();
+    assert_eq!(LIBMACAROONS_BOUND_DISCHARGE_TAG, parsed_discharge.tag);
+}
+
+#[test]
+fn v1_rejects_truncated_and_overrunning_packets() {
+    assert_eq!(Err(Error::PacketHeaderTruncated),
+               V1Token::deserialize_binary(b"").map(|_| ()));
+    assert_eq!(Err(Error::PacketHeaderTruncated),
+               V1Token::deserialize_binary(b"00").map(|_| ()));
+    assert_eq!(Err(Error::PacketLength),
+               V1Token::deserialize_binary(b"+01aidentifier x\n").map(|_| ()));
+    assert_eq!(Err(Error::PacketTooShort),
+               V1Token::deserialize_binary(b"0003identifier x\n").map(|_| ()));
+    assert_eq!(Err(Error::PacketOverrun),
+               V1Token::deserialize_binary(b"00ffidentifier x\n").map(|_| ()));
+    assert_eq!(Err(Error::MalformedPacket),
+               V1Token::deserialize_binary(b"0006ab").map(|_| ()));
+
+    // Every truncation of a valid token must fail cleanly
+    let token_data = URL_SAFE_NO_PAD.decode(example_serialized_with_first_party_caveats()).unwrap();
+
+    for length in 0..token_data.len() {
+        assert!(V1Token::deserialize_binary(&token_data[..length]).is_err());
+    }
+}
+
+// A V1 packet with the given key and value
+fn v1_packet(key: &str, value: &[u8]) -> Vec<u8> {
+    let mut packet = format!("{:04x}{} ", 4 + key.len() + value.len() + 2, key).into_bytes();
+    packet.extend_from_slice(value);
+    packet.push(b'\n');
+    packet
+}
+
+#[test]
+fn v1_rejects_repeated_caveat_packets() {
+    let caveat = [("cid", &b"caveat id"[..]), ("vid", b"verification id"), ("cl", b"location")];
+
+    let token_data = |extra: &[(&str, &[u8])]| {
+        let mut data = v1_packet("identifier", b"identifier");
+        for &(key, value) in caveat.iter().chain(extra) {
+            data.extend(v1_packet(key, value));
+        }
+        data.extend(v1_packet("signature", &EMPTY_TAG));
+        data
+    };
+
+    // The last one must not silently win
+    assert_eq!(Err(Error::PacketOrdering),
+               V1Token::deserialize_binary(&token_data(&[("vid", b"other")])).map(|_| ()));
+    assert_eq!(Err(Error::PacketOrdering),
+               V1Token::deserialize_binary(&token_data(&[("cl", b"other")])).map(|_| ()));
+
+    // Each caveat may still have its own
+    let token = V1Token::deserialize_binary(&token_data(&caveat)).unwrap();
+    assert_eq!(2, token.caveats.len());
+}
+
+#[test]
+fn v2_json_rejects_malformed_input() {
+    let malformed = ["",
+                     "{",
+                     "{\"v\":2,}",
+                     "[{\"@v\":2,\"c64\":{\"\":\"{},{S- }]\",}]\"",
+                     "{\"v\":2} {}",
+                     "{\"v\":02}",
+                     "{\"v\":2,\"i\":\"\\ud800\"}",
+                     "{\"v\":2,\"i\":\"\n\"}",
+                     "{\"v\":2,\"i\":\"a\",\"i\":\"b\"}",
+                     "{\"v\":2,\"v\":1,\"i\":\"a\"}",
+                     "{\"v\":2,\"i\":\"a\",\"c\":[{\"i\":\"a\",\"i\":\"b\"}]}"];
+
+    for json in &malformed {
+        assert_eq!(Err(Error::Json),
+                   V2Token::deserialize_json(Vec::from(*json)).map(|_| ()),
+                   "{:?}",
+                   json);
+    }
+
+    let nested = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+    assert_eq!(Err(Error::Json),
+               V2Token::deserialize_json(nested.into_bytes()).map(|_| ()));
+
+    // Numbers out of range are malformed, while other numbers just aren't a
+    // valid version
+    assert_eq!(Err(Error::Json),
+               V2Token::deserialize_json(Vec::from("{\"v\":4e3735923735908583735923}"))
+                   .map(|_| ()));
+    assert_eq!(Err(Error::UnknownVersion),
+               V2Token::deserialize_json(Vec::from("{\"v\":2.0E1}")).map(|_| ()));
+
+    let json = "{\"v\":2,\"i\":\"1e5 \\\"2e5\\\" \\u00e9\\ud83d\\ude00\",\
+                \"s64\":\"S-lnzR6gxrJrr2pKlO6bBbFYhtoLqF6MQqk8jQ4SXvw\"}";
+    assert_eq!(Vec::from("1e5 \"2e5\" \u{e9}\u{1f600}"),
+               V2Token::deserialize_json(Vec::from(json)).unwrap().identifier);
+}
+
+#[test]
+fn key_stores_rotate_keys() {
+    let mut store = MemoryKeyStore::new();
+    let verifier = verifier::Eq("test", "caveat");
+    assert_eq!(Err(Error::NoActiveKey), store.mint::<V1Token>(example_id(), None).map(|_| ()));
+
+    store.insert("2026-w41", example_key()).unwrap();
+    let old_token: V1Token = store.mint(example_id(), Some(example_uri())).unwrap();
+    let old_token = old_token.add_caveat(&example_first_party_caveat());
+
+    assert_eq!(Ok(("2026-w41", &example_id()[..])),
+               key_store::split_identifier(&old_token.identifier));
+    assert!(old_token.verify(&example_key(), &verifier).is_ok());
+
+    // Retired keys verify tokens but no longer mint them
+    store.rotate("2026-w42").unwrap();
+    store.retire("2026-w41").unwrap();
+    let new_token: V2Token = store.mint(example_id(), None).unwrap();
+    let new_token = new_token.add_caveat(&example_first_party_caveat());
+
+    assert_eq!(Ok(("2026-w42", &example_id()[..])),
+               key_store::split_identifier(&new_token.identifier));
+    assert!(store.verify(&old_token, &verifier, &[]).is_ok());
+    assert!(store.verify(&new_token, &verifier, &[]).is_ok());
+    assert!(store.verify(&new_token, verifier::Eq("test", "wrong"), &[]).is_err());
+
+    // Several keys may be active at once, the newest minting tokens
+    store.insert("2026-w43", invalid_key()).unwrap();
+    let token: V1Token = store.mint(example_id(), None).unwrap();
+    assert!(token.verify(&invalid_key(), &verifier).is_ok());
+    assert!(store.verify(&new_token, &verifier, &[]).is_ok());
+
+    store.remove("2026-w41").unwrap();
+    assert_eq!(Err(Error::UnknownKeyId), store.verify(&old_token, &verifier, &[]));
+    assert_eq!(Err(Error::UnknownKeyId), store.retire("2026-w41"));
+
+    assert_eq!(Err(Error::InvalidKeyId), store.rotate("2026-w42"));
+    assert_eq!(Err(Error::InvalidKeyId), store.rotate("2026:w44"));
+    assert_eq!(Err(Error::InvalidKeyId), store.rotate(""));
+    assert_eq!(Err(Error::MissingKeyId),
+               store.verify(&example_token(), &verifier, &[]));
+}
+
+#[test]
+fn file_key_stores_persist_keys() {
+    let path = env::temp_dir().join(format!("macaroons-keys-{}", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut store = FileKeyStore::open(&path).unwrap();
+    assert!(store.keys().is_empty());
+
+    store.insert("2026-w41", example_key()).unwrap();
+    let old_token: V1Token = store.mint(example_id(), None).unwrap();
+    store.rotate("2026-w42").unwrap();
+    store.retire("2026-w41").unwrap();
+
+    let reopened = FileKeyStore::open(&path).unwrap();
+    assert_eq!(2, reopened.keys().len());
+    assert_eq!(store.keys()[1].key, reopened.keys()[1].key);
+    assert!(reopened.keys()[0].retired);
+    assert_eq!("2026-w42", reopened.current_key().unwrap().0);
+    assert!(reopened.verify(&old_token, Func(verify_caveat), &[]).is_ok());
+
+    let new_token: V2Token = reopened.mint(example_id(), None).unwrap();
+    assert!(store.verify(&new_token, Func(verify_caveat), &[]).is_ok());
+
+    // Failed changes leave the store as it was
+    assert_eq!(Err(Error::InvalidKeyId), store.insert("2026-w42", invalid_key()));
+    assert_eq!(2, FileKeyStore::open(&path).unwrap().keys().len());
+
+    fs::write(&path, "2026-w41 expired 00\n").unwrap();
+    assert_eq!(Err(Error::KeyStore), FileKeyStore::open(&path).map(|_| ()));
+    fs::write(&path, "2026-w41 active not-hex\n").unwrap();
+    assert_eq!(Err(Error::KeyStore), FileKeyStore::open(&path).map(|_| ()));
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(format!("{}.lock", path.display())).unwrap();
+}
+
+#[test]
+fn file_key_stores_keep_concurrent_changes() {
+    let name = format!("macaroons-concurrent-keys-{}", std::process::id());
+    let path = env::temp_dir().join(&name);
+    let _ = fs::remove_file(&path);
+
+    // Changes made through a stale instance keep those made through others
+    let mut first = FileKeyStore::open(&path).unwrap();
+    let mut second = FileKeyStore::open(&path).unwrap();
+    first.insert("first", example_key()).unwrap();
+    second.insert("second", invalid_key()).unwrap();
+    assert_eq!(2, second.keys().len());
+    assert_eq!(Err(Error::InvalidKeyId), first.insert("second", example_key()));
+    first.reload().unwrap();
+    assert_eq!(2, first.keys().len());
+
+    // As do changes made at the same time
+    let threads: Vec<_> = (0..8)
+        .map(|worker| {
+            let mut store = FileKeyStore::open(&path).unwrap();
+            thread::spawn(move || {
+                for index in 0..5 {
+                    store.rotate(&format!("key-{}-{}", worker, index)).unwrap();
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(42, FileKeyStore::open(&path).unwrap().keys().len());
+
+    // Every temporary file was renamed into place
+    let leftovers = fs::read_dir(env::temp_dir())
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            file_name.starts_with(&name) && file_name.ends_with(".tmp")
+        })
+        .count();
+    assert_eq!(0, leftovers);
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(format!("{}.lock", path.display())).unwrap();
+}
@@ -0,0 +1,93 @@
+extern crate macaroons;
+
+use macaroons::caveat::Caveat;
+use macaroons::discharge;
+use macaroons::error::{Error, Result};
+use macaroons::key::RootKey;
+use macaroons::key_store::MemoryKeyStore;
+use macaroons::rng::Rng;
+use macaroons::token::Token;
+use macaroons::v2::V2Token;
+
+// Not random at all, but enough to tell where random bytes came from
+struct CountingRng {
+    fills: usize,
+}
+
+impl Rng for CountingRng {
+    fn fill(&mut self, buffer: &mut [u8]) -> Result<()> {
+        self.fills += 1;
+
+        for (index, byte) in buffer.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+
+        Ok(())
+    }
+}
+
+// Like a hardware RNG that has stopped working
+struct FailingRng;
+
+impl Rng for FailingRng {
+    fn fill(&mut self, _buffer: &mut [u8]) -> Result<()> {
+        Err(Error::Random)
+    }
+}
+
+fn third_party_caveat() -> Caveat {
+    Caveat::third_party(Vec::from("caveat key"),
+                        Vec::from("caveat id"),
+                        Vec::from("http://auth.mybank/"))
+}
+
+#[test]
+fn random_bytes_come_from_the_given_rng() {
+    let mut rng = CountingRng { fills: 0 };
+
+    let key = RootKey::generate_with_rng(&mut rng).unwrap();
+    let expected: Vec<u8> = (0..32).collect();
+    assert_eq!(&expected[..], key.as_bytes());
+    assert_eq!(1, rng.fills);
+
+    // Verification ids are prefixed by their nonce
+    let token = V2Token::new(&key, Vec::from("identifier"), None)
+        .add_caveat_with_rng(&third_party_caveat(), &mut rng)
+        .unwrap();
+    let verification_id = token.caveats[0].verification_id.clone().unwrap();
+    assert_eq!(&expected[..24], &verification_id[..24]);
+    assert_eq!(2, rng.fills);
+    assert!(token.authenticate_without_verifying(&key).is_ok());
+
+    // As are caveat ids
+    let caveat = discharge::third_party_caveat_with_rng(&key,
+                                                        Vec::from("caveat key"),
+                                                        Vec::from("user = alice"),
+                                                        Vec::from("http://auth.mybank/"),
+                                                        &mut rng)
+        .unwrap();
+    assert_eq!(&expected[..24], &caveat.caveat_id[..24]);
+    assert_eq!(3, rng.fills);
+
+    let mut keys = MemoryKeyStore::new();
+    keys.rotate_with_rng("2024-01", &mut rng).unwrap();
+    assert_eq!(4, rng.fills);
+}
+
+#[test]
+fn rng_failures_are_errors() {
+    let key = RootKey::from("this is our super secret key; only we should know it");
+
+    assert_eq!(Some(Error::Random), RootKey::generate_with_rng(&mut FailingRng).err());
+
+    let token = V2Token::new(&key, Vec::from("identifier"), None);
+    assert_eq!(Some(Error::Random),
+               token.add_caveat_with_rng(&third_party_caveat(), &mut FailingRng).err());
+
+    // First-party caveats don't need random bytes
+    let caveat = Caveat::first_party(Vec::from("account = 3735928559"));
+    assert!(token.add_caveat_with_rng(&caveat, &mut FailingRng).is_ok());
+
+    let mut keys = MemoryKeyStore::new();
+    assert_eq!(Err(Error::Random), keys.rotate_with_rng("2024-01", &mut FailingRng));
+}
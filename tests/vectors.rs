@@ -0,0 +1,197 @@
+extern crate base64;
+extern crate hex;
+extern crate macaroons;
+extern crate serde_json;
+
+use std::panic::{self, AssertUnwindSafe};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde_json::Value;
+
+use macaroons::format::{Format, Macaroon};
+use macaroons::key::RootKey;
+use macaroons::token::Token;
+use macaroons::v2::V2Token;
+use macaroons::verifier::Func;
+
+// Tokens published by other implementations, see vectors/SOURCES.md
+const VECTORS: &str = include_str!("vectors/macaroons.json");
+// Tokens generated for this crate, covering what the published ones don't.
+// They catch regressions, but say nothing about interoperability.
+const REGRESSION_VECTORS: &str = include_str!("vectors/regression.json");
+
+// Published vectors this crate is known to get wrong, by description. Each
+// must keep failing until it is fixed and removed from here.
+const KNOWN_FAILURES: &[&str] = &[];
+
+struct Vector {
+    description: String,
+    format: Format,
+    root_key: RootKey,
+    macaroon: Vec<u8>,
+    signature: Vec<u8>,
+    discharges: Vec<Vec<u8>>,
+    conditions: Vec<String>,
+    valid: bool,
+}
+
+fn string_field(vector: &Value, field: &str) -> String {
+    vector.get(field)
+        .and_then(Value::as_str)
+        .unwrap_or_else(|| panic!("missing string field {:?}", field))
+        .to_owned()
+}
+
+fn string_list_field(vector: &Value, field: &str) -> Vec<String> {
+    vector.get(field)
+        .and_then(Value::as_array)
+        .unwrap_or_else(|| panic!("missing list field {:?}", field))
+        .iter()
+        .map(|item| item.as_str().unwrap().to_owned())
+        .collect()
+}
+
+fn load_vectors() -> Vec<Vector> {
+    let mut vectors = parse_vectors(VECTORS);
+    vectors.extend(parse_vectors(REGRESSION_VECTORS));
+    vectors
+}
+
+fn parse_vectors(data: &str) -> Vec<Vector> {
+    serde_json::from_str::<Value>(data)
+        .unwrap()
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|vector| {
+            let format = match &string_field(vector, "format")[..] {
+                "v1" => Format::V1,
+                "v2" => Format::V2,
+                "v2j" => Format::V2J,
+                other => panic!("unknown format {:?}", other),
+            };
+
+            Vector {
+                description: string_field(vector, "description"),
+                format,
+                root_key: RootKey::from(string_field(vector, "root_key").into_bytes()),
+                macaroon: string_field(vector, "macaroon").into_bytes(),
+                signature: hex::decode(string_field(vector, "signature")).unwrap(),
+                discharges: string_list_field(vector, "discharges")
+                    .into_iter()
+                    .map(String::into_bytes)
+                    .collect(),
+                conditions: string_list_field(vector, "conditions"),
+                valid: vector.get("valid").and_then(Value::as_bool).unwrap(),
+            }
+        })
+        // Reading JSON tokens needs std
+        .filter(|vector| cfg!(feature = "std") || vector.format != Format::V2J)
+        .collect()
+}
+
+fn is_known_failure(vector: &Vector) -> bool {
+    KNOWN_FAILURES.contains(&&vector.description[..])
+}
+
+// Run a check of the vector, which must pass unless it is a known failure
+fn check<F: FnOnce()>(vector: &Vector, check: F) {
+    if !is_known_failure(vector) {
+        return check();
+    }
+
+    assert!(panic::catch_unwind(AssertUnwindSafe(check)).is_err(),
+            "{}: known failure now passes",
+            vector.description);
+}
+
+fn deserialize(vector: &Vector, macaroon: &[u8]) -> V2Token {
+    let macaroon = Macaroon::deserialize(macaroon.to_vec())
+        .unwrap_or_else(|e| panic!("{}: {}", vector.description, e));
+
+    assert_eq!(vector.format, macaroon.format(), "{}", vector.description);
+    macaroon.into_v2()
+}
+
+#[test]
+fn vectors_deserialize_with_expected_signature() {
+    for vector in load_vectors() {
+        check(&vector, || {
+            let token = deserialize(&vector, &vector.macaroon);
+            assert_eq!(vector.signature, token.tag.as_bytes().to_vec(), "{}", vector.description);
+        });
+    }
+}
+
+#[test]
+fn vectors_round_trip() {
+    for vector in load_vectors() {
+        check(&vector, || {
+            let token = deserialize(&vector, &vector.macaroon);
+
+            match vector.format {
+                Format::V1 => {
+                    let v1_token = Macaroon::deserialize(vector.macaroon.clone())
+                        .unwrap()
+                        .into_v1();
+                    assert_eq!(vector.macaroon,
+                               v1_token.serialize().unwrap(),
+                               "{}",
+                               vector.description);
+                }
+                Format::V2 => {
+                    let serialized = URL_SAFE_NO_PAD.encode(token.serialize().unwrap());
+                    assert_eq!(vector.macaroon, serialized.into_bytes(), "{}", vector.description);
+                }
+                #[cfg(feature = "std")]
+                Format::V2J => {
+                    let parsed_token = V2Token::deserialize_json(token.serialize_json().unwrap())
+                        .unwrap();
+                    assert_eq!(token.identifier, parsed_token.identifier, "{}", vector.description);
+                    assert_eq!(token.location, parsed_token.location, "{}", vector.description);
+                    assert_eq!(token.caveats.len(),
+                               parsed_token.caveats.len(),
+                               "{}",
+                               vector.description);
+                    for (caveat, parsed_caveat) in token.caveats.iter().zip(&parsed_token.caveats) {
+                        assert_eq!(caveat.caveat_id,
+                                   parsed_caveat.caveat_id,
+                                   "{}",
+                                   vector.description);
+                        assert_eq!(caveat.verification_id,
+                                   parsed_caveat.verification_id,
+                                   "{}",
+                                   vector.description);
+                        assert_eq!(caveat.caveat_location,
+                                   parsed_caveat.caveat_location,
+                                   "{}",
+                                   vector.description);
+                    }
+                    assert_eq!(token.tag, parsed_token.tag, "{}", vector.description);
+                }
+                #[cfg(not(feature = "std"))]
+                Format::V2J => unreachable!(),
+            }
+        });
+    }
+}
+
+#[test]
+fn vectors_verify_with_expected_outcome() {
+    for vector in load_vectors() {
+        check(&vector, || {
+            let token = deserialize(&vector, &vector.macaroon);
+            let discharges: Vec<V2Token> = vector.discharges
+                .iter()
+                .map(|discharge| deserialize(&vector, discharge))
+                .collect();
+
+            let conditions = vector.conditions.clone();
+            let verifier = Func(move |predicate: &str| conditions.iter().any(|c| c == predicate));
+            let result = token.verify_with_discharges(&vector.root_key, verifier, &discharges);
+
+            assert_eq!(vector.valid, result.is_ok(), "{}: {:?}", vector.description, result);
+        });
+    }
+}
@@ -0,0 +1,77 @@
+// Checks that secrets are wiped before their memory is freed, by scanning
+// every freed block for them. The allocator is process-wide, so these tests
+// run in their own test binary.
+extern crate macaroons;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use macaroons::caveat::Caveat;
+use macaroons::error::Result;
+use macaroons::key::RootKey;
+use macaroons::rng::Rng;
+use macaroons::token::Token;
+use macaroons::v1::V1Token;
+use macaroons::v2::V2Token;
+
+const CAVEAT_KEY: &[u8] = b"a caveat key that must be wiped before it is freed";
+
+static LEAKS: AtomicUsize = AtomicUsize::new(0);
+
+struct WipeChecker;
+
+unsafe impl GlobalAlloc for WipeChecker {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        let block = slice::from_raw_parts(pointer, layout.size());
+
+        if block.windows(CAVEAT_KEY.len()).any(|window| window == CAVEAT_KEY) {
+            LEAKS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        System.dealloc(pointer, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: WipeChecker = WipeChecker;
+
+// Without std there's no OS RNG, and the nonces needn't be random here
+struct ZeroRng;
+
+impl Rng for ZeroRng {
+    fn fill(&mut self, buffer: &mut [u8]) -> Result<()> {
+        for byte in buffer.iter_mut() {
+            *byte = 0;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn caveat_keys_are_wiped_before_being_freed() {
+    let key = RootKey::from("this is our super secret key; only we should know it");
+
+    for _ in 0..2 {
+        let caveat = Caveat::third_party(CAVEAT_KEY.to_vec(),
+                                         Vec::from("caveat id"),
+                                         Vec::from("http://auth.mybank/"));
+
+        let token = V1Token::new(&key, Vec::from("identifier"), None)
+            .add_caveat_with_rng(&caveat, &mut ZeroRng)
+            .unwrap();
+        assert!(token.authenticate_without_verifying(&key).is_ok());
+
+        let token = V2Token::new(&key, Vec::from("identifier"), None)
+            .add_caveat_with_rng(&caveat, &mut ZeroRng)
+            .unwrap();
+        assert!(token.authenticate_without_verifying(&key).is_ok());
+    }
+
+    assert_eq!(0, LEAKS.load(Ordering::SeqCst));
+}